@@ -0,0 +1,147 @@
+use ::console::{Color, Style, StyledObject};
+use indicatif::ProgressBar;
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Progress bar currently drawn at the bottom of the terminal, if any.
+static BAR: RwLock<Option<ProgressBar>> = RwLock::new(None);
+
+/// Lines kept for the dashboard while it covers the terminal, if it does.
+static CAPTURE: RwLock<Option<Arc<Captured>>> = RwLock::new(None);
+
+/// What `esay!` printed while the dashboard was shown, the oldest lines
+/// dropped past `CAPTURED_LINES`.
+#[derive(Default)]
+pub struct Captured {
+    pub lines: Mutex<VecDeque<String>>,
+}
+
+const CAPTURED_LINES: usize = 1000;
+
+/// Sends all human-readable output to stderr from now on, keeping stdout
+/// clean for the machine-readable results.
+pub fn redirect_to_stderr() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+pub fn to_stderr() -> bool {
+    TO_STDERR.load(Ordering::Relaxed)
+}
+
+/// Colors the output from now on if `wanted`, unless NO_COLOR is set
+/// (https://no-color.org), the terminal is dumb or either stream the output
+/// goes to isn't a terminal. Call after `redirect_to_stderr`.
+pub fn init_colors(wanted: bool) {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let dumb = env::var_os("TERM").is_some_and(|term| term == "dumb");
+    let terminals = io::stderr().is_terminal() && (to_stderr() || io::stdout().is_terminal());
+    let enabled = wanted && !no_color && !dumb && terminals;
+    ::console::set_colors_enabled(enabled);
+    ::console::set_colors_enabled_stderr(enabled);
+}
+
+/// Green for 2xx, yellow for 3xx and 4xx, red for 5xx.
+pub fn status<D>(code: u16, text: D) -> StyledObject<D> {
+    let style = Style::new();
+    match code {
+        200..=299 => style.green(),
+        300..=499 => style.yellow(),
+        500..=599 => style.red(),
+        _ => style,
+    }
+    .apply_to(text)
+}
+
+pub fn error<D>(text: D) -> StyledObject<D> {
+    Style::new().red().apply_to(text)
+}
+
+/// "Thread N", in one of 8 colors so that the lines of a thread are easy to
+/// follow.
+pub fn thread(id: usize) -> StyledObject<String> {
+    const COLORS: [Color; 4] = [Color::Cyan, Color::Magenta, Color::Blue, Color::Yellow];
+    // Clear of green and red, which tell how requests went
+    let index = id.saturating_sub(1) % 8;
+    let style = Style::new().fg(COLORS[index % 4]);
+    let style = if index >= 4 { style.bright() } else { style };
+    style.apply_to(format!("Thread {:>2}", id))
+}
+
+/// Makes `say!` and `esay!` print above `bar` until `hide_bar` is called.
+pub fn show_bar(bar: ProgressBar) {
+    *BAR.write().unwrap_or_else(|err| err.into_inner()) = Some(bar);
+}
+
+pub fn hide_bar() {
+    *BAR.write().unwrap_or_else(|err| err.into_inner()) = None;
+}
+
+/// Keeps `esay!` lines in `captured` instead of printing them, and drops
+/// `say!` lines, until `release` is called.
+pub fn capture(captured: Arc<Captured>) {
+    *CAPTURE.write().unwrap_or_else(|err| err.into_inner()) = Some(captured);
+}
+
+pub fn release() {
+    *CAPTURE.write().unwrap_or_else(|err| err.into_inner()) = None;
+}
+
+/// Runs `print` with the progress bar cleared, so the output doesn't end up
+/// interleaved with it; the bar is redrawn afterwards.
+pub fn suspend<R>(print: impl FnOnce() -> R) -> R {
+    match &*BAR.read().unwrap_or_else(|err| err.into_inner()) {
+        Some(bar) => bar.suspend(print),
+        None => print(),
+    }
+}
+
+pub fn say(args: fmt::Arguments) {
+    if CAPTURE
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .is_some()
+    {
+        return;
+    }
+    suspend(|| {
+        if to_stderr() {
+            eprintln!("{}", args);
+        } else {
+            println!("{}", args);
+        }
+    })
+}
+
+pub fn esay(args: fmt::Arguments) {
+    if let Some(captured) = &*CAPTURE.read().unwrap_or_else(|err| err.into_inner()) {
+        let mut lines = captured.lines.lock().unwrap_or_else(|err| err.into_inner());
+        lines.extend(args.to_string().lines().map(str::to_owned));
+        let excess = lines.len().saturating_sub(CAPTURED_LINES);
+        lines.drain(..excess);
+        return;
+    }
+    suspend(|| eprintln!("{}", args))
+}
+
+/// `println!` for human-readable output, which goes to stderr once
+/// `redirect_to_stderr` has been called.
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::console::say(format_args!($($arg)*))
+    };
+}
+
+/// `eprintln!` that keeps clear of the progress bar and the dashboard.
+#[macro_export]
+macro_rules! esay {
+    ($($arg:tt)*) => {
+        $crate::console::esay(format_args!($($arg)*))
+    };
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// One CSV row, by column name.
+pub type Row = HashMap<String, String>;
+
+/// Rows of a `DATA_FILE`, whose columns become template variables; every
+/// request takes one row.
+pub struct DataFeed {
+    rows: Vec<Row>,
+    /// Next row for round-robin picks, shared so that every row is used once
+    /// before any is used again.
+    cursor: AtomicUsize,
+}
+
+impl DataFeed {
+    /// Reads `path`, a CSV file with a header row. Fails if it has no rows,
+    /// or with the line of the first malformed one.
+    pub fn load(path: &Path) -> Result<DataFeed, String> {
+        let mut reader = csv::Reader::from_path(path).map_err(describe)?;
+        let columns = reader.headers().map_err(describe)?.clone();
+        let rows = reader
+            .records()
+            .map(|record| {
+                let record = record.map_err(describe)?;
+                Ok(columns
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(column, value)| (column.to_string(), value.to_string()))
+                    .collect())
+            })
+            .collect::<Result<Vec<Row>, String>>()?;
+        if rows.is_empty() {
+            return Err("no data rows".into());
+        }
+        Ok(DataFeed {
+            rows,
+            cursor: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn rows(&self) -> &[Row] {
+        &self.rows
+    }
+
+    /// Next row in file order, starting over after the last one.
+    pub fn next_row(&self) -> &Row {
+        &self.rows[self.cursor.fetch_add(1, Ordering::Relaxed) % self.rows.len()]
+    }
+
+    /// Next row in file order, `None` once every row was taken.
+    pub fn take_row(&self) -> Option<&Row> {
+        self.rows.get(self.cursor.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The csv crate names the record and byte offset too; the line is what
+/// helps find the mistake in an editor.
+fn describe(err: csv::Error) -> String {
+    match err.kind() {
+        csv::ErrorKind::UnequalLengths {
+            pos: Some(pos),
+            expected_len,
+            len,
+        } => format!(
+            "line {}: {} fields, but the header has {}",
+            pos.line(),
+            len,
+            expected_len
+        ),
+        csv::ErrorKind::Utf8 { pos: Some(pos), .. } => {
+            format!("line {}: not valid UTF-8", pos.line())
+        }
+        _ => err.to_string(),
+    }
+}
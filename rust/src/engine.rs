@@ -0,0 +1,1758 @@
+use crate::config::{
+    AuthConfig, Config, Http2, HttpVersion, Login, PickStrategy, Protocol, Verbosity,
+};
+use crate::console;
+use crate::rate_limit::TokenBucket;
+use crate::request_log::RequestLog;
+use crate::stats::{Activity, Counters, ErrorCategory, WorkerStats};
+use crate::template::{RequestSource, Vars};
+use crate::token_refresh::Refresh;
+use crate::websocket;
+use hyper::client::connect::HttpInfo;
+use reqwest::header::{HeaderMap, AUTHORIZATION, PROXY_AUTHORIZATION};
+use reqwest::{Method, StatusCode, Version};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How much of every response body `Verbosity::Trace` prints.
+const DEBUG_BODY_BYTES: usize = 512;
+/// Characters of a credential left visible in the debug output.
+const DEBUG_SECRET_CHARS: usize = 8;
+
+/// Set once a run should end early, e.g. on Ctrl+C. Clones share the flag,
+/// and every run has its own.
+#[derive(Clone, Default)]
+pub struct Stop(Arc<AtomicBool>);
+
+impl Stop {
+    /// Asks every worker to stop after its in-flight request. Returns whether
+    /// a stop had already been requested.
+    pub fn request(&self) -> bool {
+        self.0.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn requested(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// State shared by every worker of a run.
+struct Shared {
+    config: Arc<Config>,
+    source: Arc<RequestSource>,
+    counters: Arc<Counters>,
+    stop: Stop,
+    limiter: Option<TokenBucket>,
+    request_log: Option<Arc<RequestLog>>,
+    started: Instant,
+    /// End of the run (or warmup) in duration mode.
+    deadline: Option<Instant>,
+    /// Requests left in the warmup phase, `None` for the measured run.
+    warmup_remaining: Option<AtomicUsize>,
+    /// When the last worker was started after a ramp-up, and how many
+    /// requests had completed by then.
+    ramped_up: OnceLock<(Instant, u64)>,
+    /// Size of the multipart or form body, the same for every request.
+    fixed_body_size: Option<u64>,
+    /// A worker found the data file used up, with `DATA_MODE=stop`.
+    data_exhausted: AtomicBool,
+}
+
+impl Shared {
+    /// How long to wait before sending the next request to honour the
+    /// target rate. Pacing happens before the timer starts, so it never
+    /// counts as latency.
+    fn pacing_delay(&self) -> Option<Duration> {
+        self.limiter.as_ref().map(TokenBucket::reserve)
+    }
+}
+
+/// What a worker keeps of a response.
+struct Reply {
+    status: StatusCode,
+    /// Protocol the response came over.
+    version: Version,
+    /// At least one redirect was followed to get here.
+    redirected: bool,
+    /// Why the body failed validation, if it did.
+    invalid_body: Option<String>,
+    /// Response headers, only kept at `Verbosity::Debug`.
+    headers: Option<HeaderMap>,
+    /// Size of the body, which is always read to the end.
+    body_bytes: u64,
+    /// Up to `DEBUG_BODY_BYTES` of the body, only kept at `Verbosity::Trace`.
+    body_start: Option<Vec<u8>>,
+}
+
+impl Reply {
+    /// Only successful responses are validated, so only their body is read.
+    fn needs_body(&self, config: &Config) -> bool {
+        config.validates_body() && config.success_codes.contains(self.status.as_u16())
+    }
+
+    /// How much of the body to keep: none unless it's checked or traced.
+    fn body_limit(&self, config: &Config) -> usize {
+        let checked = if self.needs_body(config) {
+            config.max_body_bytes
+        } else {
+            0
+        };
+        if config.verbosity >= Verbosity::Trace {
+            checked.max(DEBUG_BODY_BYTES)
+        } else {
+            checked
+        }
+    }
+
+    /// Takes in the body of `size` bytes, of which `body` is the start kept
+    /// by `read_body`, `None` if it couldn't be read.
+    fn take_body(&mut self, config: &Config, size: u64, body: Option<Vec<u8>>) {
+        self.body_bytes = size;
+        if self.needs_body(config) {
+            let complete = body
+                .as_deref()
+                .filter(|_| size <= config.max_body_bytes as u64);
+            self.check_body(config, complete);
+        }
+        if config.verbosity >= Verbosity::Trace {
+            self.body_start = body.map(|mut body| {
+                body.truncate(DEBUG_BODY_BYTES);
+                body
+            });
+        }
+    }
+
+    /// Applies RESPONSE_MUST_CONTAIN, RESPONSE_MUST_NOT_CONTAIN and
+    /// EXPECT_JSON_PATH to `body`, `None` if it was over MAX_BODY_BYTES.
+    fn check_body(&mut self, config: &Config, body: Option<&[u8]>) {
+        let Some(body) = body else {
+            self.invalid_body = Some(format!(
+                "body larger than MAX_BODY_BYTES ({} bytes)",
+                config.max_body_bytes
+            ));
+            return;
+        };
+        let text = String::from_utf8_lossy(body);
+        if let Some(needle) = &config.response_must_contain {
+            if !text.contains(needle.as_str()) {
+                self.invalid_body = Some(format!("body does not contain '{}'", needle));
+                return;
+            }
+        }
+        if let Some(needle) = &config.response_must_not_contain {
+            if text.contains(needle.as_str()) {
+                self.invalid_body = Some(format!("body contains '{}'", needle));
+                return;
+            }
+        }
+        if let Some(path) = &config.expect_json_path {
+            self.invalid_body = path.check(body, config.expect_json_value.as_ref()).err();
+        }
+    }
+}
+
+/// One virtual user, i.e. an OS thread or a Tokio task.
+struct Worker {
+    id: usize,
+    shared: Arc<Shared>,
+    sent: usize,
+    /// Round-robin position in `config.target_urls`.
+    url_cursor: usize,
+    /// Index in `config.payload_files` of the file the current request
+    /// sends, `None` without PAYLOAD_DIR.
+    payload: Option<usize>,
+    /// Whether the current request was sent again with a refreshed token.
+    refreshed: bool,
+    /// Seeded per worker, so the random URL mix is the same on every run.
+    rng: fastrand::Rng,
+    /// Per-worker measurements, merged by the engine after join
+    stats: WorkerStats,
+}
+
+impl Worker {
+    fn new(id: usize, shared: Arc<Shared>) -> Worker {
+        Worker {
+            id,
+            shared,
+            sent: 0,
+            // Staggered so the workers don't all hit the same URL first
+            url_cursor: id - 1,
+            payload: None,
+            refreshed: false,
+            rng: fastrand::Rng::with_seed(id as u64),
+            stats: WorkerStats::new(),
+        }
+    }
+
+    /// Whether this worker should send another request.
+    fn has_more(&self) -> bool {
+        let more = self.wants_more();
+        if !more {
+            self.shared.counters.set_activity(self.id, Activity::Done);
+        }
+        more
+    }
+
+    /// `has_more`, short of marking the worker done.
+    fn wants_more(&self) -> bool {
+        if self.shared.stop.requested() {
+            return false;
+        }
+        if let Some(remaining) = &self.shared.warmup_remaining {
+            return self
+                .shared
+                .deadline
+                .is_none_or(|deadline| Instant::now() < deadline)
+                && remaining
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                    .is_ok();
+        }
+        self.shared
+            .config
+            .requests_per_thread
+            .is_none_or(|limit| self.sent < limit)
+            && self
+                .shared
+                .deadline
+                .is_none_or(|deadline| Instant::now() < deadline)
+    }
+
+    /// Pause before the next request, like a user reading the last response.
+    /// Not before the first one, and never part of the measured latency.
+    fn think_time(&self) -> Option<Duration> {
+        let config = &self.shared.config;
+        if self.sent == 0 || (config.think_time.is_zero() && config.think_time_jitter.is_zero()) {
+            return None;
+        }
+        Some(config.think_time + config.think_time_jitter.mul_f64(fastrand::f64()))
+    }
+
+    /// Index in `config.target_urls` of the URL for the next request.
+    fn next_url(&mut self) -> usize {
+        let count = self.shared.config.target_urls.len();
+        match self.shared.config.url_strategy {
+            PickStrategy::RoundRobin => {
+                let url = self.url_cursor % count;
+                self.url_cursor = url + 1;
+                url
+            }
+            PickStrategy::Random => {
+                let mut pick = self.rng.f64();
+                for (url, weight) in self.shared.config.url_weights.iter().enumerate() {
+                    if pick < *weight {
+                        return url;
+                    }
+                    pick -= weight;
+                }
+                // Rounding left a sliver past the last weight
+                count - 1
+            }
+        }
+    }
+
+    /// URL and body of the next request, to the `url`th target URL. `None`
+    /// once `DATA_MODE=stop` used up the data file, which ends this worker.
+    fn prepare(&mut self, url: usize) -> Option<(String, Option<Vec<u8>>)> {
+        let shared = &*self.shared;
+        let Ok(row) = shared.source.pick_row(&mut self.rng) else {
+            if !shared.data_exhausted.swap(true, Ordering::Relaxed)
+                && shared.config.verbosity > Verbosity::Quiet
+            {
+                say!(
+                    "[{:>8.3}s] {} | Every row of the data file was used, stopping",
+                    shared.started.elapsed().as_secs_f64(),
+                    console::thread(self.id)
+                );
+            }
+            shared.counters.set_activity(self.id, Activity::Done);
+            return None;
+        };
+        shared.counters.set_activity(self.id, Activity::Sending);
+        self.refreshed = false;
+        let payload = match shared.config.scenarios.get(url) {
+            Some(scenario) => scenario.payload,
+            None => Some(shared.source.pick_payload(&mut self.rng)),
+        };
+        self.payload = payload.filter(|_| shared.config.payload_dir.is_some());
+        let vars = Vars {
+            thread_id: self.id,
+            request_num: self.sent + 1,
+            row,
+            payload,
+        };
+        Some(shared.source.prepare(url, &vars))
+    }
+
+    /// Size of the request body sent with every attempt of a request.
+    fn body_size(&self, body: Option<&Vec<u8>>) -> u64 {
+        self.shared
+            .fixed_body_size
+            .or(body.map(|body| body.len() as u64))
+            .unwrap_or(0)
+    }
+
+    /// Backoff before retry number `attempt + 1` of a request that failed at
+    /// the transport level or with a retryable status (`status`), `None` if
+    /// it shouldn't be retried. The delay doubles with every attempt; the
+    /// jitter keeps workers that failed together from retrying in lockstep.
+    fn retry_delay(&self, attempt: u32, status: Option<StatusCode>) -> Option<Duration> {
+        let config = &self.shared.config;
+        let retryable = status.is_none_or(|status| config.retry_codes.contains(status.as_u16()));
+        if !retryable || attempt >= config.max_retries {
+            return None;
+        }
+        let backoff = config.retry_backoff.saturating_mul(1 << attempt.min(16));
+        Some(backoff / 2 + backoff.mul_f64(fastrand::f64() / 2.0))
+    }
+
+    /// Reply to a request for `requested` that ended up at `final_url`,
+    /// noting the connection it came over.
+    fn reply(
+        &mut self,
+        requested: &str,
+        status: StatusCode,
+        version: Version,
+        final_url: &reqwest::Url,
+        headers: &HeaderMap,
+        connection: Option<&HttpInfo>,
+    ) -> Reply {
+        // Told apart by their client port, as the peer is the same for all
+        if let Some(connection) = connection {
+            self.stats.connections.insert(connection.local_addr());
+        }
+        let config = &self.shared.config;
+        // Parsed because reqwest normalizes URLs, e.g. adds a trailing slash
+        let redirected = config.follow_redirects
+            && reqwest::Url::parse(requested).is_ok_and(|requested| &requested != final_url);
+        Reply {
+            status,
+            version,
+            redirected,
+            invalid_body: None,
+            headers: (config.verbosity >= Verbosity::Debug).then(|| headers.clone()),
+            body_bytes: 0,
+            body_start: None,
+        }
+    }
+
+    /// Counts one more request to the `url`th URL, which took `elapsed` and
+    /// transferred `sent` and `received` body bytes.
+    fn measure(&mut self, elapsed: Duration, url: usize, sent: u64, received: u64) {
+        self.sent += 1;
+        let dur_us = elapsed.as_micros() as u64;
+        self.stats.latency.saturating_record(dur_us.max(1));
+        self.shared.counters.record_latency(self.id, dur_us);
+        self.shared
+            .counters
+            .set_activity(self.id, Activity::Waiting);
+        self.stats.bytes_sent += sent;
+        self.stats.bytes_received += received;
+        let url_stats = self.stats.per_url.entry(url).or_default();
+        url_stats.latency.saturating_record(dur_us.max(1));
+        url_stats.bytes_sent += sent;
+        url_stats.bytes_received += received;
+        if let Some(payload) = self.payload {
+            let payload_stats = self.stats.per_payload.entry(payload).or_default();
+            payload_stats.latency.saturating_record(dur_us.max(1));
+            payload_stats.bytes_sent += sent;
+            payload_stats.bytes_received += received;
+        }
+    }
+
+    /// Start of the log line of the request just measured.
+    fn line(&self, url: usize, retries: u32) -> String {
+        let shared = &*self.shared;
+        let config = &shared.config;
+        let elapsed_run = shared.started.elapsed().as_secs_f64();
+        let request = match config.requests_per_thread {
+            Some(limit) if shared.warmup_remaining.is_none() => {
+                format!("{:>3}/{}", self.sent, limit)
+            }
+            _ => format!("{:>3}", self.sent),
+        };
+        let mut phase = String::new();
+        if shared.warmup_remaining.is_some() {
+            phase.push_str(" (warmup)");
+        }
+        if retries > 0 {
+            phase.push_str(&format!(" (retries: {})", retries));
+        }
+        // With a single URL the banner already names it
+        let mut target = match config.scenarios.get(url) {
+            Some(scenario) => format!(
+                "{}: {} {}",
+                scenario.name, scenario.method, config.target_urls[url]
+            ),
+            None if config.target_urls.len() > 1 => {
+                format!("{} {}", config.request_label(), config.target_urls[url])
+            }
+            None => config.request_label(),
+        };
+        if let Some(payload) = self.payload {
+            target.push_str(&format!(
+                " | Payload: {}",
+                config.payload_files[payload].name
+            ));
+        }
+        format!(
+            "[{:>8.3}s] {} | Request {}{} | {}",
+            elapsed_run,
+            console::thread(self.id),
+            request,
+            phase,
+            target
+        )
+    }
+
+    /// Prints a request about to be sent with its headers, credentials
+    /// masked, at `Verbosity::Debug`.
+    fn debug_request(&self, method: &Method, url: &reqwest::Url, headers: &HeaderMap) {
+        let config = &self.shared.config;
+        if config.verbosity < Verbosity::Debug {
+            return;
+        }
+        let prefix = format!("[DEBUG] {} |", console::thread(self.id));
+        esay!("{} > {} {}", prefix, method, url);
+        for (name, value) in headers {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let secret = name == AUTHORIZATION
+                || name == PROXY_AUTHORIZATION
+                || *name == config.token_header.name;
+            if secret && value.chars().count() > DEBUG_SECRET_CHARS {
+                let visible: String = value.chars().take(DEBUG_SECRET_CHARS).collect();
+                esay!("{} > {}: {}***", prefix, name, visible);
+            } else {
+                esay!("{} > {}: {}", prefix, name, value);
+            }
+        }
+    }
+
+    /// Prints the status and headers of a response at `Verbosity::Debug`, and
+    /// the start of its body at `Verbosity::Trace`.
+    fn debug_reply(&self, reply: &Reply) {
+        let Some(headers) = &reply.headers else {
+            return;
+        };
+        let prefix = format!("[DEBUG] {} |", console::thread(self.id));
+        esay!(
+            "{} < {:?} {}",
+            prefix,
+            reply.version,
+            console::status(reply.status.as_u16(), reply.status)
+        );
+        for (name, value) in headers {
+            esay!(
+                "{} < {}: {}",
+                prefix,
+                name,
+                String::from_utf8_lossy(value.as_bytes())
+            );
+        }
+        if let Some(body) = &reply.body_start {
+            let more = if reply.body_bytes > body.len() as u64 {
+                format!(" ({} of {} bytes)", body.len(), reply.body_bytes)
+            } else {
+                String::new()
+            };
+            esay!(
+                "{} < body{}: {}",
+                prefix,
+                more,
+                String::from_utf8_lossy(body).escape_debug()
+            );
+        }
+    }
+
+    /// Counts, records and logs the outcome of a single request to the `url`th
+    /// URL, which took `elapsed` including `retries` retries and their backoff.
+    /// `sent` is the size of the bodies of every attempt that connected. The
+    /// headers came in after `ttfb` if there was a response.
+    fn record(
+        &mut self,
+        elapsed: Duration,
+        ttfb: Option<Duration>,
+        url: usize,
+        retries: u32,
+        sent: u64,
+        res: Result<Reply, reqwest::Error>,
+    ) {
+        let received = res.as_ref().map_or(0, |reply| reply.body_bytes);
+        self.measure(elapsed, url, sent, received);
+        if let Ok(reply) = &res {
+            self.debug_reply(reply);
+        }
+        if let Some(ttfb) = ttfb {
+            let ttfb_us = ttfb.as_micros() as u64;
+            self.stats.ttfb.saturating_record(ttfb_us.max(1));
+        }
+        let shared = &*self.shared;
+        let config = &shared.config;
+        self.stats.retries += u64::from(retries);
+
+        match res {
+            Ok(Reply {
+                status,
+                version,
+                redirected,
+                invalid_body: Some(reason),
+                ..
+            }) => {
+                if redirected {
+                    self.stats.redirected += 1;
+                }
+                *self
+                    .stats
+                    .http_versions
+                    .entry(version_name(version))
+                    .or_insert(0) += 1;
+                *self.stats.status_codes.entry(status.as_u16()).or_insert(0) += 1;
+                shared.counters.record_validation_failure(self.id);
+                self.stats
+                    .error_message(&format!("validation failed: {}", reason));
+                self.stats.count(url, self.payload, false);
+                if let Some(log) = &shared.request_log {
+                    log.record(
+                        self.id,
+                        self.sent,
+                        (url, self.payload),
+                        Some(status.as_u16()),
+                        Some("validation"),
+                        elapsed,
+                    );
+                }
+                if config.verbosity >= Verbosity::Normal {
+                    esay!(
+                        "{} | Status: {} | {}",
+                        self.line(url, retries),
+                        console::status(status.as_u16(), status),
+                        console::error(format!("Validation failed: {}", reason))
+                    );
+                }
+            }
+            Ok(Reply {
+                status,
+                version,
+                redirected,
+                invalid_body: None,
+                ..
+            }) => {
+                if redirected {
+                    self.stats.redirected += 1;
+                }
+                *self
+                    .stats
+                    .http_versions
+                    .entry(version_name(version))
+                    .or_insert(0) += 1;
+                *self.stats.status_codes.entry(status.as_u16()).or_insert(0) += 1;
+                let success = config.success_codes.contains(status.as_u16());
+                if success {
+                    shared.counters.record_success(self.id);
+                    if retries > 0 {
+                        self.stats.recovered += 1;
+                    }
+                    if self.refreshed {
+                        self.stats.recovered_after_refresh += 1;
+                    }
+                } else {
+                    shared.counters.record_error(self.id, ErrorCategory::Http);
+                    self.stats.error_message(&format!("status {}", status));
+                }
+                self.stats.count(url, self.payload, success);
+                if let Some(log) = &shared.request_log {
+                    log.record(
+                        self.id,
+                        self.sent,
+                        (url, self.payload),
+                        Some(status.as_u16()),
+                        None,
+                        elapsed,
+                    );
+                }
+                if success && config.verbosity >= Verbosity::Verbose {
+                    say!(
+                        "{} | Status: {}",
+                        self.line(url, retries),
+                        console::status(status.as_u16(), status)
+                    );
+                } else if !success && config.verbosity >= Verbosity::Normal {
+                    esay!(
+                        "{} | Status: {}",
+                        self.line(url, retries),
+                        console::status(status.as_u16(), status)
+                    );
+                }
+            }
+            Err(err) => {
+                // Timeouts included: their latency above shows the timeout ceiling
+                let kind = error_kind(&err, config);
+                *self.stats.errors.entry(kind).or_insert(0) += 1;
+                self.stats.error_message(&format!("{}: {}", kind, err));
+                shared
+                    .counters
+                    .record_error(self.id, ErrorCategory::of_kind(kind));
+                self.stats.count(url, self.payload, false);
+                if let Some(log) = &shared.request_log {
+                    log.record(
+                        self.id,
+                        self.sent,
+                        (url, self.payload),
+                        None,
+                        Some(kind),
+                        elapsed,
+                    );
+                }
+                if config.verbosity >= Verbosity::Normal {
+                    esay!(
+                        "{} | {}",
+                        self.line(url, retries),
+                        console::error(format!("Error ({}): {}", kind, err))
+                    );
+                }
+            }
+        }
+    }
+
+    /// Counts and logs a token refresh after a 401, and marks the current
+    /// request as sent again if there's a new token to send it with.
+    fn record_refresh(&mut self, outcome: Refresh) -> bool {
+        let config = &self.shared.config;
+        let line = format!(
+            "[{:>8.3}s] {} | Token refresh after 401",
+            self.shared.started.elapsed().as_secs_f64(),
+            console::thread(self.id)
+        );
+        match outcome {
+            Refresh::Refreshed => {
+                self.stats.token_refreshes += 1;
+                if config.verbosity >= Verbosity::Verbose {
+                    say!("{}", line);
+                }
+            }
+            Refresh::Replaced => {}
+            Refresh::Failed(err) => {
+                self.stats.token_refreshes += 1;
+                self.stats.failed_token_refreshes += 1;
+                if config.verbosity >= Verbosity::Normal {
+                    esay!("{} {}", line, console::error(format!("failed: {}", err)));
+                }
+                return false;
+            }
+        }
+        self.refreshed = true;
+        true
+    }
+
+    /// Counts and logs the login of this worker, which took `elapsed`.
+    fn record_login(&mut self, elapsed: Duration, res: Result<StatusCode, reqwest::Error>) {
+        let config = &self.shared.config;
+        let line = format!(
+            "[{:>8.3}s] {} | Login ({:.2} ms)",
+            self.shared.started.elapsed().as_secs_f64(),
+            console::thread(self.id),
+            elapsed.as_secs_f64() * 1000.0
+        );
+        match res {
+            Ok(status) if config.success_codes.contains(status.as_u16()) => {
+                self.stats.logins += 1;
+                if config.verbosity >= Verbosity::Verbose {
+                    say!(
+                        "{} | Status: {}",
+                        line,
+                        console::status(status.as_u16(), status)
+                    );
+                }
+            }
+            Ok(status) => {
+                self.stats.failed_logins += 1;
+                if config.verbosity >= Verbosity::Normal {
+                    esay!(
+                        "{} | Status: {}",
+                        line,
+                        console::status(status.as_u16(), status)
+                    );
+                }
+            }
+            Err(err) => {
+                self.stats.failed_logins += 1;
+                if config.verbosity >= Verbosity::Normal {
+                    esay!(
+                        "{} | {}",
+                        line,
+                        console::error(format!("Error ({}): {}", error_kind(&err, config), err))
+                    );
+                }
+            }
+        }
+    }
+
+    /// Counts, records and logs a WebSocket message of `sent` bytes to the
+    /// `url`th URL whose reply of `received` bytes took `elapsed`. A reply that
+    /// failed `WS_EXPECT_CONTAINS` comes with the reason.
+    fn record_ws(
+        &mut self,
+        elapsed: Duration,
+        url: usize,
+        (sent, received): (u64, u64),
+        res: Result<Option<String>, websocket::Error>,
+    ) {
+        self.measure(elapsed, url, sent, received);
+        let shared = &*self.shared;
+        let config = &shared.config;
+        let counters = &shared.counters;
+
+        let error = match &res {
+            Ok(None) => {
+                counters.record_success(self.id);
+                None
+            }
+            Ok(Some(reason)) => {
+                counters.record_validation_failure(self.id);
+                self.stats
+                    .error_message(&format!("validation failed: {}", reason));
+                Some("validation")
+            }
+            Err(err) => {
+                counters.record_error(self.id, ErrorCategory::of_kind(err.kind));
+                *self.stats.errors.entry(err.kind).or_insert(0) += 1;
+                self.stats.error_message(&format!("{}: {}", err.kind, err));
+                Some(err.kind)
+            }
+        };
+        self.stats.count(url, None, error.is_none());
+        if let Some(log) = &shared.request_log {
+            log.record(self.id, self.sent, (url, None), None, error, elapsed);
+        }
+        match res {
+            Ok(None) if config.verbosity >= Verbosity::Verbose => {
+                say!("{} | Reply received", self.line(url, 0));
+            }
+            Ok(Some(reason)) if config.verbosity >= Verbosity::Normal => {
+                esay!(
+                    "{} | {}",
+                    self.line(url, 0),
+                    console::error(format!("Validation failed: {}", reason))
+                );
+            }
+            Err(err) if config.verbosity >= Verbosity::Normal => {
+                esay!(
+                    "{} | {}",
+                    self.line(url, 0),
+                    console::error(format!("Error ({}): {}", err.kind, err))
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reads the body of `resp` to the end and returns its size and up to
+/// `limit` bytes of its start: the rest is read without being kept, so a huge
+/// response isn't buffered whole just to be checked.
+async fn read_body(
+    mut resp: reqwest::Response,
+    limit: usize,
+) -> Result<(u64, Option<Vec<u8>>), reqwest::Error> {
+    let mut body = Vec::new();
+    let mut size = 0;
+    while let Some(chunk) = resp.chunk().await? {
+        size += chunk.len() as u64;
+        let room = limit.saturating_sub(body.len()).min(chunk.len());
+        body.extend_from_slice(&chunk[..room]);
+    }
+    Ok((size, Some(body)))
+}
+
+/// Same as `read_body` for the blocking client.
+#[cfg(feature = "blocking")]
+fn read_body_blocking(
+    mut resp: reqwest::blocking::Response,
+    limit: usize,
+) -> Result<(u64, Option<Vec<u8>>), reqwest::Error> {
+    use std::io::{self, Read};
+
+    let mut body = Vec::new();
+    let read = (&mut resp)
+        .take(limit as u64)
+        .read_to_end(&mut body)
+        .and_then(|read| io::copy(&mut resp, &mut io::sink()).map(|rest| read as u64 + rest));
+    match read {
+        Ok(size) => Ok((size, Some(body))),
+        // The blocking client wraps its own errors, e.g. timeouts, which is
+        // all it returns; anything else fails the checks rather than letting
+        // a partial body pass them
+        Err(err) => match err
+            .into_inner()
+            .map(|inner| inner.downcast::<reqwest::Error>())
+        {
+            Some(Ok(err)) => Err(*err),
+            _ => Ok((0, None)),
+        },
+    }
+}
+
+/// Size of the multipart or form body of `config`, which reqwest encodes
+/// itself, by building a request that is never sent. `None` for other bodies.
+fn fixed_body_size(config: &Config) -> Option<u64> {
+    let request = reqwest::Client::new().post("http://localhost/");
+    let request = if let Some(multipart) = &config.multipart {
+        request.multipart(multipart.form())
+    } else if let Some(fields) = &config.form_fields {
+        request.form(fields)
+    } else {
+        return None;
+    };
+    let request = request.build().ok()?;
+    let encoded = request.body().and_then(reqwest::Body::as_bytes);
+    match encoded {
+        Some(bytes) => Some(bytes.len() as u64),
+        None => request
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok(),
+    }
+}
+
+/// Without following, 3xx responses are final and checked like any status.
+fn redirect_policy(config: &Config) -> reqwest::redirect::Policy {
+    if config.follow_redirects {
+        reqwest::redirect::Policy::limited(config.max_redirects)
+    } else {
+        reqwest::redirect::Policy::none()
+    }
+}
+
+/// Display name of an HTTP protocol version.
+fn version_name(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "other",
+    }
+}
+
+/// Short, stable name for the kind of transport error.
+fn error_kind(err: &reqwest::Error, config: &Config) -> &'static str {
+    if err.is_connect() && err.is_timeout() {
+        "connect_timeout"
+    } else if err.is_timeout() {
+        "timeout"
+    } else if err.is_connect() {
+        let proxied = err.url().is_some_and(|url| config.proxied(url));
+        connect_error_kind(err, proxied, config.local_address(1).is_some())
+    } else if err.is_redirect() {
+        "redirect"
+    } else if err.is_body() || err.is_decode() {
+        "body"
+    } else if err.is_request() {
+        "request"
+    } else {
+        "other"
+    }
+}
+
+/// Tells apart why a connection couldn't be set up. reqwest doesn't expose
+/// it, so this goes by its causes: the TCP connector reports a refused
+/// connection as an `io::Error` and prefixes resolver failures with "dns
+/// error"; anything else going wrong while connecting to an https URL
+/// happened in the TLS handshake. When the request was `proxied`, the
+/// connection is the one to the proxy, and the tunnel through it to an https
+/// URL (or the SOCKS5 handshake) comes before the TLS one. With a
+/// `forced_family`, from IP_VERSION or a LOCAL_BIND_ADDR, a host without an
+/// address of it or a network that can't carry it is told apart.
+fn connect_error_kind(err: &reqwest::Error, proxied: bool, forced_family: bool) -> &'static str {
+    let mut tcp_or_dns = false;
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        // hyper reports no address left of the family as NotConnected
+        if forced_family
+            && cause.downcast_ref::<std::io::Error>().is_some_and(|io| {
+                matches!(
+                    io.kind(),
+                    std::io::ErrorKind::NotConnected
+                        | std::io::ErrorKind::NetworkUnreachable
+                        | std::io::ErrorKind::AddrNotAvailable
+                )
+            })
+        {
+            return "ip_version";
+        }
+        if cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|io| io.kind() == std::io::ErrorKind::ConnectionRefused)
+        {
+            return if proxied {
+                "proxy"
+            } else {
+                "connection_refused"
+            };
+        }
+        let message = cause.to_string();
+        if message.starts_with("dns error") {
+            return if proxied { "proxy" } else { "dns" };
+        }
+        // reqwest's messages for a CONNECT the proxy didn't accept, and any
+        // failure of a SOCKS5 proxy, which reaches the target itself
+        if message.contains("tunnel")
+            || message == "proxy authentication required"
+            || message.starts_with("socks connect error")
+        {
+            return "proxy";
+        }
+        tcp_or_dns |= message.starts_with("tcp connect error");
+        source = cause.source();
+    }
+    let https = err.url().is_some_and(|url| url.scheme() == "https");
+    if proxied && (tcp_or_dns || !https) {
+        "proxy"
+    } else if https && !tcp_or_dns {
+        "tls"
+    } else {
+        "connect"
+    }
+}
+
+/// Sends `config.warmup_requests` requests and/or keeps sending for
+/// `config.warmup_duration`, spread over all workers and without ramp-up.
+/// Their measurements are discarded; the counters still see them, so callers
+/// should reset those afterwards. Returns the number of requests sent, or
+/// why an HTTP client couldn't be built.
+pub fn warmup(
+    config: Arc<Config>,
+    source: Arc<RequestSource>,
+    counters: Arc<Counters>,
+    stop: Stop,
+) -> Result<u64, reqwest::Error> {
+    let remaining = match config.warmup_requests {
+        0 => usize::MAX,
+        requests => requests,
+    };
+    let shared = Arc::new(Shared {
+        limiter: config.target_rps.map(TokenBucket::new),
+        warmup_remaining: Some(AtomicUsize::new(remaining)),
+        deadline: config.warmup_duration.map(|d| Instant::now() + d),
+        fixed_body_size: fixed_body_size(&config),
+        data_exhausted: AtomicBool::new(false),
+        config,
+        source,
+        counters,
+        stop,
+        request_log: None,
+        started: Instant::now(),
+        ramped_up: OnceLock::new(),
+    });
+    run_workers(&shared)?;
+    Ok(shared.counters.snapshot().completed())
+}
+
+/// Runs every worker to completion and returns their merged measurements,
+/// or why an HTTP client couldn't be built.
+pub fn run(
+    config: Arc<Config>,
+    source: Arc<RequestSource>,
+    counters: Arc<Counters>,
+    stop: Stop,
+    request_log: Option<Arc<RequestLog>>,
+) -> Result<WorkerStats, reqwest::Error> {
+    let shared = Arc::new(Shared {
+        deadline: config.test_duration.map(|d| Instant::now() + d),
+        limiter: config.target_rps.map(TokenBucket::new),
+        fixed_body_size: fixed_body_size(&config),
+        data_exhausted: AtomicBool::new(false),
+        config,
+        source,
+        counters,
+        stop,
+        request_log,
+        started: Instant::now(),
+        warmup_remaining: None,
+        ramped_up: OnceLock::new(),
+    });
+
+    let stats = run_workers(&shared);
+    if let Some(log) = &shared.request_log {
+        log.close_or_warn();
+    }
+    let mut stats = stats?;
+    stats.steady_state_rps = shared.ramped_up.get().and_then(|(at, completed)| {
+        let window = at.elapsed().as_secs_f64();
+        let total = shared.counters.snapshot().completed();
+        // A run that ended during the ramp has no steady state
+        let ended_early = shared.deadline.is_some_and(|deadline| *at >= deadline);
+        (window > 0.0 && !ended_early).then(|| (total - completed) as f64 / window)
+    });
+    Ok(stats)
+}
+
+fn run_workers(shared: &Arc<Shared>) -> Result<WorkerStats, reqwest::Error> {
+    if shared.config.protocol == Protocol::Ws {
+        return Ok(run_websocket(shared));
+    }
+    #[cfg(feature = "blocking")]
+    if !shared.config.use_async {
+        return run_blocking(shared);
+    }
+    run_async(shared)
+}
+
+/// Delay between two worker starts: the ramp-up split evenly over the
+/// workers. The warmup phase always starts everything at once.
+fn spawn_interval(shared: &Shared) -> Option<Duration> {
+    match (shared.config.ramp_up, &shared.warmup_remaining) {
+        (Some(ramp_up), None) => Some(ramp_up / shared.config.num_threads as u32),
+        _ => None,
+    }
+}
+
+/// Logs a worker started during the ramp-up and, once it's the last one,
+/// marks the start of the steady state.
+fn ramp_progress(shared: &Shared, thread_id: usize) {
+    let num_threads = shared.config.num_threads;
+    if shared.config.verbosity > Verbosity::Quiet {
+        say!(
+            "[{:>8.3}s] {} | Started ({}/{} workers running)",
+            shared.started.elapsed().as_secs_f64(),
+            console::thread(thread_id),
+            thread_id,
+            num_threads
+        );
+    }
+    if thread_id == num_threads {
+        let completed = shared.counters.snapshot().completed();
+        let _ = shared.ramped_up.set((Instant::now(), completed));
+    }
+}
+
+/// One OS thread per worker, each with its own blocking client unless
+/// SHARED_CLIENT asks for one for all of them.
+#[cfg(feature = "blocking")]
+fn run_blocking(shared: &Arc<Shared>) -> Result<WorkerStats, reqwest::Error> {
+    use std::thread;
+
+    let mut handles = Vec::with_capacity(shared.config.num_threads);
+    let interval = spawn_interval(shared);
+    let clients = match shared.config.shared_client {
+        true => shared_clients(&shared.config, blocking_client)?,
+        false => Vec::new(),
+    };
+
+    for thread_id in 1..=shared.config.num_threads {
+        if let Some(interval) = interval.filter(|_| thread_id > 1) {
+            thread::sleep(interval);
+        }
+        if shared.stop.requested() {
+            break;
+        }
+        if interval.is_some() {
+            ramp_progress(shared, thread_id);
+        }
+        let mut worker = Worker::new(thread_id, Arc::clone(shared));
+        let client = clients.get((thread_id - 1) % clients.len().max(1)).cloned();
+
+        let handle = thread::spawn(move || {
+            let config = &worker.shared.config;
+            let client = match client {
+                Some(client) => client,
+                None => blocking_client(config, config.local_address(thread_id))?,
+            };
+
+            // Not paced nor counted as a request, the session is a precondition
+            if let Some(login) = &config.login {
+                let start_login = Instant::now();
+                let res = blocking_login_request(&client, config, login)
+                    .send()
+                    .map(|resp| resp.status());
+                worker.record_login(start_login.elapsed(), res);
+            }
+
+            while worker.has_more() {
+                if let Some(pause) = worker.think_time() {
+                    thread::sleep(pause);
+                }
+                if let Some(delay) = worker.shared.pacing_delay() {
+                    thread::sleep(delay);
+                }
+                // Retries and their backoff are part of the recorded latency
+                let start_req = Instant::now();
+                let url = worker.next_url();
+                let Some((target, body)) = worker.prepare(url) else {
+                    break;
+                };
+                let config = Arc::clone(&worker.shared.config);
+                let mut retries = 0;
+                let mut sent = 0;
+
+                let res = loop {
+                    let (token, request) =
+                        blocking_request(&client, &config, worker.id, (url, &target), &body);
+                    let res = request.and_then(|request| {
+                        worker.debug_request(request.method(), request.url(), request.headers());
+                        client.execute(request)
+                    });
+                    if !res.as_ref().is_err_and(reqwest::Error::is_connect) {
+                        sent += worker.body_size(body.as_ref());
+                    }
+                    let status = res.as_ref().ok().map(|resp| resp.status());
+                    // Sent once more with a new token, whether this or another worker
+                    // got it
+                    if status == Some(StatusCode::UNAUTHORIZED) && !worker.refreshed {
+                        if let (Some(refresh), Some(shared)) =
+                            (&config.token_refresh, config.auth.shared_token())
+                        {
+                            let outcome =
+                                refresh.refresh_blocking(&client, shared, token.as_deref());
+                            if worker.record_refresh(outcome) {
+                                continue;
+                            }
+                        }
+                    }
+                    match worker.retry_delay(retries, status) {
+                        Some(delay) => {
+                            retries += 1;
+                            thread::sleep(delay);
+                        }
+                        None => break res,
+                    }
+                };
+                let ttfb = res.is_ok().then(|| start_req.elapsed());
+                let res = res.and_then(|resp| {
+                    let mut reply = worker.reply(
+                        &target,
+                        resp.status(),
+                        resp.version(),
+                        resp.url(),
+                        resp.headers(),
+                        resp.extensions().get::<HttpInfo>(),
+                    );
+                    let (size, body) = read_body_blocking(resp, reply.body_limit(&config))?;
+                    reply.take_body(&config, size, body);
+                    Ok(reply)
+                });
+                let elapsed = start_req.elapsed();
+                worker.record(elapsed, ttfb, url, retries, sent, res);
+            }
+
+            Ok(worker.stats)
+        });
+        handles.push(handle);
+    }
+
+    let mut stats = WorkerStats::new();
+    for handle in handles {
+        stats.merge(&handle.join().expect("thread panicked")?);
+    }
+    Ok(stats)
+}
+
+/// Defines a function building a client, async or blocking, with every
+/// connection setting of `config`. reqwest's two client builders have the
+/// same methods but no trait in common.
+macro_rules! client_fn {
+    ($(#[$attr:meta])* $vis:vis fn $name:ident() -> $client:ty, $apply_tls:ident) => {
+        $(#[$attr])*
+        $vis fn $name(
+            config: &Config,
+            local_address: Option<IpAddr>,
+        ) -> Result<$client, reqwest::Error> {
+            let mut builder = config
+                .tls
+                .$apply_tls(<$client>::builder())
+                .timeout(config.request_timeout)
+                .connect_timeout(config.connect_timeout)
+                .redirect(redirect_policy(config));
+            if let Some(proxy) = &config.proxy {
+                builder = builder.proxy(proxy.clone());
+            }
+            for pinned in &config.resolve {
+                builder = builder.resolve_to_addrs(&pinned.host, &pinned.socket_addrs());
+            }
+            if let Some(local) = local_address {
+                builder = builder.local_address(local);
+            }
+            if let Some(max_idle) = config.pool_max_idle_per_host {
+                builder = builder.pool_max_idle_per_host(max_idle);
+            }
+            if let Some(timeout) = config.pool_idle_timeout {
+                builder = builder.pool_idle_timeout(timeout);
+            }
+            builder = match config.http2 {
+                Some(Http2::PriorKnowledge) => builder.http2_prior_knowledge(),
+                // reqwest only offers h2 via ALPN with rustls
+                Some(Http2::Alpn) => builder.use_rustls_tls().https_only(true),
+                None if config.http_version == HttpVersion::Http1 => builder.http1_only(),
+                None => builder,
+            };
+            builder.cookie_store(config.cookie_jar).build()
+        }
+    };
+}
+
+client_fn!(
+    /// Blocking client with every connection setting of `config`.
+    #[cfg(feature = "blocking")]
+    fn blocking_client() -> reqwest::blocking::Client,
+    apply_blocking
+);
+
+client_fn!(
+    /// Async client with every connection setting of `config`.
+    pub fn async_client() -> reqwest::Client,
+    apply
+);
+
+/// Clients shared by the workers, one per LOCAL_BIND_ADDR entry, so that
+/// the `n`th worker takes the `n % len`th like `Config::local_address`.
+fn shared_clients<C>(
+    config: &Config,
+    build: fn(&Config, Option<IpAddr>) -> Result<C, reqwest::Error>,
+) -> Result<Vec<C>, reqwest::Error> {
+    (1..=config.local_addrs.len().max(1))
+        .map(|worker| build(config, config.local_address(worker)))
+        .collect()
+}
+
+/// Every worker is a task on a multi-threaded Tokio runtime, all sharing one
+/// async client (and its connection pool), or one per LOCAL_BIND_ADDR entry.
+fn run_async(shared: &Arc<Shared>) -> Result<WorkerStats, reqwest::Error> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build Tokio runtime");
+
+    runtime.block_on(async {
+        let clients = shared_clients(&shared.config, async_client)?;
+
+        let mut handles = Vec::with_capacity(shared.config.num_threads);
+        let interval = spawn_interval(shared);
+
+        for thread_id in 1..=shared.config.num_threads {
+            if let Some(interval) = interval.filter(|_| thread_id > 1) {
+                tokio::time::sleep(interval).await;
+            }
+            if shared.stop.requested() {
+                break;
+            }
+            if interval.is_some() {
+                ramp_progress(shared, thread_id);
+            }
+            // A shared client would share its cookie jar too
+            let client = if shared.config.cookie_jar {
+                async_client(&shared.config, shared.config.local_address(thread_id))?
+            } else {
+                clients[(thread_id - 1) % clients.len()].clone()
+            };
+            let mut worker = Worker::new(thread_id, Arc::clone(shared));
+
+            let handle = tokio::task::spawn(async move {
+                let config = &worker.shared.config;
+                if let Some(login) = &config.login {
+                    let start_login = Instant::now();
+                    let res = login_request(&client, config, login)
+                        .send()
+                        .await
+                        .map(|resp| resp.status());
+                    worker.record_login(start_login.elapsed(), res);
+                }
+                while worker.has_more() {
+                    if let Some(pause) = worker.think_time() {
+                        tokio::time::sleep(pause).await;
+                    }
+                    if let Some(delay) = worker.shared.pacing_delay() {
+                        tokio::time::sleep(delay).await;
+                    }
+                    // Retries and their backoff are part of the recorded latency
+                    let start_req = Instant::now();
+                    let url = worker.next_url();
+                    let Some((target, body)) = worker.prepare(url) else {
+                        break;
+                    };
+                    let config = Arc::clone(&worker.shared.config);
+                    let mut retries = 0;
+                    let mut sent = 0;
+
+                    let res = loop {
+                        let (token, request) =
+                            async_request(&client, &config, worker.id, (url, &target), &body);
+                        let res = match request {
+                            Ok(request) => {
+                                worker.debug_request(
+                                    request.method(),
+                                    request.url(),
+                                    request.headers(),
+                                );
+                                client.execute(request).await
+                            }
+                            Err(err) => Err(err),
+                        };
+                        if !res.as_ref().is_err_and(reqwest::Error::is_connect) {
+                            sent += worker.body_size(body.as_ref());
+                        }
+                        let status = res.as_ref().ok().map(|resp| resp.status());
+                        // Sent once more with a new token, whether this or another worker
+                        // got it
+                        if status == Some(StatusCode::UNAUTHORIZED) && !worker.refreshed {
+                            if let (Some(refresh), Some(shared)) =
+                                (&config.token_refresh, config.auth.shared_token())
+                            {
+                                let outcome =
+                                    refresh.refresh(&client, shared, token.as_deref()).await;
+                                if worker.record_refresh(outcome) {
+                                    continue;
+                                }
+                            }
+                        }
+                        match worker.retry_delay(retries, status) {
+                            Some(delay) => {
+                                retries += 1;
+                                tokio::time::sleep(delay).await;
+                            }
+                            None => break res,
+                        }
+                    };
+                    let ttfb = res.is_ok().then(|| start_req.elapsed());
+                    let res = async {
+                        let resp = res?;
+                        let mut reply = worker.reply(
+                            &target,
+                            resp.status(),
+                            resp.version(),
+                            resp.url(),
+                            resp.headers(),
+                            resp.extensions().get::<HttpInfo>(),
+                        );
+                        let (size, body) = read_body(resp, reply.body_limit(&config)).await?;
+                        reply.take_body(&config, size, body);
+                        Ok(reply)
+                    }
+                    .await;
+                    let elapsed = start_req.elapsed();
+                    worker.record(elapsed, ttfb, url, retries, sent, res);
+                }
+
+                worker.stats
+            });
+            handles.push(handle);
+        }
+
+        let mut stats = WorkerStats::new();
+        for handle in handles {
+            stats.merge(&handle.await.expect("task panicked"));
+        }
+        Ok(stats)
+    })
+}
+
+/// Defines the functions building the requests of a worker for one of
+/// reqwest's clients, async or blocking, whose builders have the same methods
+/// but no trait in common. `$form` makes the multipart form of the client.
+macro_rules! request_fns {
+    (
+        $(#[$attr:meta])*
+        $request:ident, $login_request:ident,
+        $client:ty, $builder:ty, $built:ty, $form:ident
+    ) => {
+        /// The request the `id`th worker sends to the `url`th target URL, at
+        /// `target` once rendered, with its body, auth, headers and
+        /// signature, and the bearer token it carries.
+        $(#[$attr])*
+        fn $request(
+            client: &$client,
+            config: &Config,
+            id: usize,
+            (url, target): (usize, &str),
+            body: &Option<Vec<u8>>,
+        ) -> (Option<String>, Result<$built, reqwest::Error>) {
+            let mut request_builder = client.request(config.method_of(url).into(), target);
+
+            if let Some(multipart) = &config.multipart {
+                request_builder = request_builder.multipart(multipart.$form());
+            } else if let Some(fields) = &config.form_fields {
+                request_builder = request_builder.form(fields);
+            } else if let Some(body) = body {
+                request_builder = request_builder
+                    .header("Content-Type", "application/json")
+                    .body(body.clone());
+            }
+
+            let token = config.auth.bearer_token(id);
+            if let AuthConfig::Basic { username, password } = &config.auth {
+                request_builder = request_builder.basic_auth(username, password.as_ref());
+            } else if let Some(token) = &token {
+                request_builder = request_builder
+                    .header(&config.token_header.name, config.token_header.value(token));
+            }
+
+            // Replaces the defaults above when set
+            let headers = config.headers_of(url);
+            if !headers.is_empty() {
+                request_builder = request_builder.headers(headers.clone());
+            }
+
+            let request = request_builder.build().map(|mut request| {
+                // Signed last, over the request exactly as it goes out
+                if let Some(signer) = &config.signer {
+                    let body = request.body().and_then(|body| body.as_bytes());
+                    let headers = signer.headers(
+                        request.method().as_str(),
+                        request.url().as_str(),
+                        body.unwrap_or_default(),
+                    );
+                    request.headers_mut().extend(headers);
+                }
+                request
+            });
+            (token, request)
+        }
+
+        /// The LOGIN_URL request that starts the session of a worker.
+        $(#[$attr])*
+        fn $login_request(client: &$client, config: &Config, login: &Login) -> $builder {
+            let mut request_builder = client.post(&login.url);
+            if let Some(payload) = &login.payload {
+                request_builder = request_builder
+                    .header("Content-Type", "application/json")
+                    .body(payload.clone());
+            }
+            if !config.headers.is_empty() {
+                request_builder = request_builder.headers(config.headers.clone());
+            }
+            request_builder
+        }
+    };
+}
+
+request_fns!(
+    async_request,
+    login_request,
+    reqwest::Client,
+    reqwest::RequestBuilder,
+    reqwest::Request,
+    form
+);
+
+request_fns!(
+    #[cfg(feature = "blocking")]
+    blocking_request,
+    blocking_login_request,
+    reqwest::blocking::Client,
+    reqwest::blocking::RequestBuilder,
+    reqwest::blocking::Request,
+    blocking_form
+);
+
+/// What the addresses of a target host are, for a dry run.
+pub struct Lookup {
+    pub host: String,
+    pub port: u16,
+    /// Of the IP_VERSION family only.
+    pub addrs: Vec<IpAddr>,
+    /// Given by RESOLVE rather than looked up.
+    pub pinned: bool,
+}
+
+/// Looks up every host the target URLs go to, once per host and port, the
+/// way the client will: RESOLVE pins are taken as they are, and only
+/// addresses of the IP_VERSION family count. Hosts given as an address,
+/// reached through a proxy (which resolves them itself) or templated aren't
+/// looked up.
+pub fn lookup(config: &Config) -> Result<Vec<Lookup>, String> {
+    let mut lookups: Vec<Lookup> = Vec::new();
+    for url in &config.target_urls {
+        let Ok(parsed) = reqwest::Url::parse(url) else {
+            continue;
+        };
+        let (Some(host), Some(port)) = (parsed.host_str(), parsed.port_or_known_default()) else {
+            continue;
+        };
+        if config.proxied(&parsed)
+            || host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .is_ok()
+            || lookups
+                .iter()
+                .any(|done| done.host == host && done.port == port)
+        {
+            continue;
+        }
+        let (addrs, pinned) = match config.resolve.iter().find(|pinned| pinned.matches(url)) {
+            Some(pinned) => (pinned.addrs.clone(), true),
+            None => {
+                let found = (host, port)
+                    .to_socket_addrs()
+                    .map_err(|err| format!("can't resolve {}: {}", host, err))?;
+                (found.map(|addr| addr.ip()).collect(), false)
+            }
+        };
+        let addrs: Vec<IpAddr> = (addrs.into_iter())
+            .filter(|addr| config.ip_version.allows(addr))
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("{} has no {} address", host, config.ip_version));
+        }
+        lookups.push(Lookup {
+            host: host.to_string(),
+            port,
+            addrs,
+            pinned,
+        });
+    }
+    Ok(lookups)
+}
+
+/// The response to the one request of a dry run.
+pub struct Preflight {
+    pub method: Method,
+    /// The first target URL a worker would send to, rendered.
+    pub url: String,
+    pub status: StatusCode,
+    pub version: &'static str,
+    pub headers: HeaderMap,
+    pub elapsed: Duration,
+    /// Why the body failed validation, if it did.
+    pub invalid_body: Option<String>,
+}
+
+/// Sends the first request of the first worker once, with the same client,
+/// auth, headers and body checks as a run but without retries, and without
+/// counting it anywhere. The async client is used whatever the engine, as
+/// it's set up from the same settings. A LOGIN_URL request is sent first,
+/// and has to succeed. Errors say what kind of transport error it was.
+pub fn preflight(config: Arc<Config>, source: Arc<RequestSource>) -> Result<Preflight, String> {
+    let shared = Arc::new(Shared {
+        counters: Arc::new(Counters::new(config.num_threads)),
+        limiter: None,
+        deadline: None,
+        warmup_remaining: None,
+        fixed_body_size: None,
+        data_exhausted: AtomicBool::new(false),
+        config,
+        source,
+        stop: Stop::default(),
+        request_log: None,
+        started: Instant::now(),
+        ramped_up: OnceLock::new(),
+    });
+    let config = &shared.config;
+    let describe = |err: reqwest::Error| format!("{} ({})", err, error_kind(&err, config));
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build Tokio runtime");
+    runtime.block_on(async {
+        let client = async_client(config, config.local_address(1))
+            .map_err(|err| format!("can't build the HTTP client: {}", err))?;
+        if let Some(login) = &config.login {
+            let status = (login_request(&client, config, login).send().await)
+                .map_err(|err| format!("login to {} failed: {}", login.url, describe(err)))?
+                .status();
+            if !config.success_codes.contains(status.as_u16()) {
+                return Err(format!("login to {} failed with {}", login.url, status));
+            }
+        }
+
+        let mut worker = Worker::new(1, Arc::clone(&shared));
+        let url = worker.next_url();
+        let (target, body) = worker
+            .prepare(url)
+            .ok_or("the data file has no rows to send")?;
+        let (_, request) = async_request(&client, config, worker.id, (url, &target), &body);
+        let request = request.map_err(describe)?;
+        let method = request.method().clone();
+
+        let start = Instant::now();
+        let resp = client.execute(request).await.map_err(describe)?;
+        let headers = resp.headers().clone();
+        let mut reply = worker.reply(
+            &target,
+            resp.status(),
+            resp.version(),
+            resp.url(),
+            &headers,
+            None,
+        );
+        let (size, body) = read_body(resp, reply.body_limit(config))
+            .await
+            .map_err(describe)?;
+        reply.take_body(config, size, body);
+        Ok(Preflight {
+            method,
+            url: target,
+            status: reply.status,
+            version: version_name(reply.version),
+            headers,
+            elapsed: start.elapsed(),
+            invalid_body: reply.invalid_body,
+        })
+    })
+}
+
+/// Every worker is a Tokio task with a WebSocket connection of its own, opened
+/// before its first request and again after a failed one, since a late reply
+/// would otherwise be taken for the next one's. Only the message and its
+/// reply are timed; a failed connection attempt counts as a failed request.
+fn run_websocket(shared: &Arc<Shared>) -> WorkerStats {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build Tokio runtime");
+
+    runtime.block_on(async {
+        let mut handles = Vec::with_capacity(shared.config.num_threads);
+        let interval = spawn_interval(shared);
+
+        for thread_id in 1..=shared.config.num_threads {
+            if let Some(interval) = interval.filter(|_| thread_id > 1) {
+                tokio::time::sleep(interval).await;
+            }
+            if shared.stop.requested() {
+                break;
+            }
+            if interval.is_some() {
+                ramp_progress(shared, thread_id);
+            }
+            let mut worker = Worker::new(thread_id, Arc::clone(shared));
+
+            let handle = tokio::task::spawn(async move {
+                // The URL index the connection was opened to, and the connection
+                let mut connection: Option<(usize, websocket::Connection)> = None;
+
+                while worker.has_more() {
+                    if let Some(pause) = worker.think_time() {
+                        tokio::time::sleep(pause).await;
+                    }
+                    if let Some(delay) = worker.shared.pacing_delay() {
+                        tokio::time::sleep(delay).await;
+                    }
+                    let url = match &connection {
+                        Some((url, _)) => *url,
+                        None => worker.next_url(),
+                    };
+                    let Some((target, body)) = worker.prepare(url) else {
+                        break;
+                    };
+                    let config = Arc::clone(&worker.shared.config);
+                    let (_, stream) = match connection.as_mut() {
+                        Some(connected) => connected,
+                        None => {
+                            let start_connect = Instant::now();
+                            match websocket::connect(&config, &target, worker.id).await {
+                                Ok(stream) => connection.insert((url, stream)),
+                                Err(err) => {
+                                    let elapsed = start_connect.elapsed();
+                                    worker.record_ws(elapsed, url, (0, 0), Err(err));
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    let message = websocket::message(&config, body.unwrap_or_default());
+
+                    let sent = message.len() as u64;
+
+                    let start_req = Instant::now();
+                    let res = websocket::round_trip(stream, message, config.request_timeout).await;
+                    let elapsed = start_req.elapsed();
+                    let received = res.as_ref().map_or(0, |reply| reply.len() as u64);
+                    if res.is_err() {
+                        connection = None;
+                    }
+                    let res = res.map(|reply| {
+                        let needle = config.ws_expect_contains.as_ref()?;
+                        (!websocket::reply_text(&reply).contains(needle.as_str()))
+                            .then(|| format!("reply does not contain '{}'", needle))
+                    });
+                    worker.record_ws(elapsed, url, (sent, received), res);
+                }
+
+                if let Some((_, mut stream)) = connection {
+                    // Best effort, the server may be gone already
+                    let _ = stream.close(None).await;
+                }
+                worker.stats
+            });
+            handles.push(handle);
+        }
+
+        let mut stats = WorkerStats::new();
+        for handle in handles {
+            stats.merge(&handle.await.expect("task panicked"));
+        }
+        stats
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, Settings, Verbosity};
+    use std::collections::BTreeMap;
+    use std::net::TcpListener;
+
+    /// The error kinds of one request to `url` with the `settings`, with each
+    /// engine.
+    fn error_kinds(url: &str, settings: impl Fn() -> Settings) -> [BTreeMap<String, u64>; 2] {
+        [false, true].map(|use_async| {
+            let config = Config::try_from(Settings {
+                target_url: Some(url.into()),
+                num_threads: Some(1),
+                requests_per_thread: Some(1),
+                use_async: Some(use_async),
+                verbosity: Some(Verbosity::Quiet),
+                ..settings()
+            })
+            .unwrap();
+            crate::run(config).unwrap().summary.errors
+        })
+    }
+
+    /// A port nothing listens on, once the listener is dropped.
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[test]
+    fn closed_port_is_connection_refused() {
+        let url = format!("http://127.0.0.1:{}/", closed_port());
+        for errors in error_kinds(&url, Settings::default) {
+            assert_eq!(errors, BTreeMap::from([("connection_refused".into(), 1)]));
+        }
+    }
+
+    #[test]
+    fn unknown_host_is_dns() {
+        // .invalid is reserved never to resolve
+        for errors in error_kinds("http://no-such-host.invalid/", Settings::default) {
+            assert_eq!(errors, BTreeMap::from([("dns".into(), 1)]));
+        }
+    }
+
+    #[test]
+    fn unreachable_proxy_is_proxy() {
+        for scheme in ["http", "socks5h"] {
+            let proxy_url = format!("{}://127.0.0.1:{}", scheme, closed_port());
+            let settings = || Settings {
+                proxy_url: Some(proxy_url.clone()),
+                ..Settings::default()
+            };
+            for url in ["http://target.test/", "https://target.test/"] {
+                for errors in error_kinds(url, settings) {
+                    assert_eq!(errors, BTreeMap::from([("proxy".into(), 1)]), "{}", scheme);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use crate::config::{AuthConfig, Config};
+use crate::engine;
+use crate::template::is_template;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// Asks for just enough of the schema to tell that there is one.
+const INTROSPECTION_QUERY: &str = "query IntrospectionQuery { __schema { queryType { name } mutationType { name } types { name } } }";
+
+/// What introspection found out about the schema.
+pub struct Schema {
+    pub types: usize,
+    pub query_type: Option<String>,
+    pub mutation_type: Option<String>,
+}
+
+/// The `{"query": ..., "variables": ...}` envelope sent with every request,
+/// as the payload. Tags in the variables file make it a template rendered per
+/// request; the query itself is always sent as it is.
+pub fn payload(query_path: &Path, variables_path: Option<&Path>) -> Result<Vec<u8>, String> {
+    let read = |what: &str, path: &Path| {
+        fs::read_to_string(path)
+            .map_err(|err| format!("could not read {} {}: {}", what, path.display(), err))
+    };
+    let query = read("GraphQL query", query_path)?;
+    let query = serde_json::to_string(query.trim()).expect("strings serialize");
+    let variables = match variables_path {
+        Some(path) => {
+            let variables = read("GraphQL variables", path)?;
+            // Templated variables are only JSON once rendered
+            if !is_template(&variables) {
+                serde_json::from_str::<Value>(&variables).map_err(|err| {
+                    format!("invalid GraphQL variables {}: {}", path.display(), err)
+                })?;
+            }
+            variables.trim().to_string()
+        }
+        None => "{}".to_string(),
+    };
+    // Keeps Tera off the query once the payload is a template
+    let query = if is_template(&query) || is_template(&variables) {
+        format!("{{% raw %}}{}{{% endraw %}}", query)
+    } else {
+        query
+    };
+    Ok(format!("{{\"query\": {}, \"variables\": {}}}", query, variables).into_bytes())
+}
+
+/// Asks the first target URL for its schema, so a wrong endpoint or one
+/// without GraphQL fails before the run rather than with every request.
+pub fn introspect(config: &Config) -> Result<Schema, String> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build Tokio runtime");
+    let body = serde_json::json!({ "query": INTROSPECTION_QUERY }).to_string();
+
+    let response: Value = runtime.block_on(async {
+        let mut request = engine::async_client(config, config.local_address(1))
+            .map_err(|err| err.to_string())?
+            .post(&config.target_urls[0])
+            .header("Content-Type", "application/json")
+            .body(body);
+        // With a token per thread, the first thread's
+        if let AuthConfig::Basic { username, password } = &config.auth {
+            request = request.basic_auth(username, password.as_ref());
+        } else if let Some(token) = config.auth.bearer_token(1) {
+            request = request.header(&config.token_header.name, config.token_header.value(&token));
+        }
+        if !config.headers.is_empty() {
+            request = request.headers(config.headers.clone());
+        }
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        let status = response.status();
+        let text = response.text().await.map_err(|err| err.to_string())?;
+        serde_json::from_str(&text)
+            .map_err(|err| format!("status {}, response is not JSON: {}", status, err))
+    })?;
+
+    if let Some(errors) = response["errors"]
+        .as_array()
+        .filter(|errors| !errors.is_empty())
+    {
+        let messages: Vec<&str> = errors
+            .iter()
+            .map(|error| error["message"].as_str().unwrap_or("unknown error"))
+            .collect();
+        return Err(messages.join("; "));
+    }
+    let schema = response
+        .pointer("/data/__schema")
+        .filter(|schema| schema.is_object())
+        .ok_or("no data.__schema in the response")?;
+    let type_name = |pointer: &str| {
+        schema
+            .pointer(pointer)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    };
+    Ok(Schema {
+        types: schema["types"].as_array().map_or(0, Vec::len),
+        query_type: type_name("/queryType/name"),
+        mutation_type: type_name("/mutationType/name"),
+    })
+}
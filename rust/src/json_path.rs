@@ -0,0 +1,94 @@
+use serde_json::Value;
+use std::fmt;
+
+/// A JSON path selecting a single value, such as `$.data.items[0].status` or
+/// `$['content-type']`. Wildcards, slices and filters are not supported.
+pub struct JsonPath {
+    text: String,
+    steps: Vec<Step>,
+}
+
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+impl JsonPath {
+    pub fn parse(text: &str) -> Result<JsonPath, String> {
+        let invalid = |reason: &str| format!("invalid JSON path '{}': {}", text, reason);
+        let mut rest = text
+            .trim()
+            .strip_prefix('$')
+            .ok_or_else(|| invalid("it must start with $"))?;
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after_dot) = rest.strip_prefix('.') {
+                let end = after_dot.find(['.', '[']).unwrap_or(after_dot.len());
+                if end == 0 {
+                    return Err(invalid("empty key after '.'"));
+                }
+                steps.push(Step::Key(after_dot[..end].to_string()));
+                rest = &after_dot[end..];
+            } else if let Some(after_bracket) = rest.strip_prefix('[') {
+                let end = after_bracket
+                    .find(']')
+                    .ok_or_else(|| invalid("unclosed '['"))?;
+                let inner = &after_bracket[..end];
+                let quoted = ['\'', '"']
+                    .into_iter()
+                    .find_map(|quote| inner.strip_prefix(quote)?.strip_suffix(quote));
+                steps.push(match quoted {
+                    Some(key) => Step::Key(key.to_string()),
+                    None => Step::Index(inner.parse().map_err(|_| {
+                        invalid("expected an index or a quoted key between brackets")
+                    })?),
+                });
+                rest = &after_bracket[end + 1..];
+            } else {
+                return Err(invalid("expected '.' or '[' after a step"));
+            }
+        }
+        Ok(JsonPath {
+            text: text.trim().to_string(),
+            steps,
+        })
+    }
+
+    /// The value the path points to in `root`, if there is one.
+    pub fn select<'a>(&self, root: &'a Value) -> Option<&'a Value> {
+        self.steps.iter().try_fold(root, |value, step| match step {
+            Step::Key(key) => value.get(key),
+            Step::Index(index) => value.get(index),
+        })
+    }
+
+    /// Checks that `body` is JSON with a value at this path, equal to
+    /// `expected` if given. Numbers are compared by value, so `1` matches
+    /// `1.0`.
+    pub fn check(&self, body: &[u8], expected: Option<&Value>) -> Result<(), String> {
+        let root: Value =
+            serde_json::from_slice(body).map_err(|err| format!("body is not JSON: {}", err))?;
+        let actual = self
+            .select(&root)
+            .ok_or_else(|| format!("{} not found in the body", self))?;
+        match expected {
+            Some(expected) if !same_value(actual, expected) => {
+                Err(format!("{} is {} instead of {}", self, actual, expected))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+fn same_value(actual: &Value, expected: &Value) -> bool {
+    match (actual.as_f64(), expected.as_f64()) {
+        (Some(actual), Some(expected)) => actual == expected,
+        _ => actual == expected,
+    }
+}
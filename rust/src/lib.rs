@@ -0,0 +1,590 @@
+//! HTTP and WebSocket load generator, usable from code as well as from the
+//! `load_tester_rust` command line tool.
+//!
+//! A test is described by a [`Config`], loaded from flags, environment
+//! variables and a config file with [`Config::load`] or built in code from
+//! [`config::Settings`], and run with [`run`]:
+//!
+//! ```no_run
+//! use load_tester_rust::config::{Settings, Verbosity};
+//! use load_tester_rust::Config;
+//!
+//! let config = Config::try_from(Settings {
+//!     target_url: Some("http://localhost:3000/api/foo".into()),
+//!     num_threads: Some(4),
+//!     verbosity: Some(Verbosity::Quiet),
+//!     ..Settings::default()
+//! })?;
+//! let result = load_tester_rust::run(config)?;
+//! println!("{} of {} requests failed", result.summary.failure_count, result.summary.total_requests);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Every run keeps its own counters and state, so runs one after the other
+//! in the same process don't affect each other. [`LoadTest`] splits a run in
+//! two, for callers that want to show what is about to run, or to stop it
+//! from elsewhere, once everything is loaded.
+
+#[macro_use]
+pub mod console;
+pub mod config;
+mod data;
+pub mod engine;
+mod graphql;
+mod json_path;
+mod metrics;
+mod oauth2;
+mod progress;
+mod rate_limit;
+pub mod report;
+pub mod request_log;
+pub mod sampler;
+mod signing;
+pub mod stats;
+mod statsd;
+pub mod summary;
+pub mod template;
+#[cfg(test)]
+mod testing;
+mod token_refresh;
+pub mod tui;
+mod websocket;
+
+pub use config::{Config, ConfigError};
+pub use summary::Summary;
+
+use chrono::Utc;
+use config::{AuthConfig, Protocol, Verbosity, WsMessageType};
+use data::DataFeed;
+use engine::Stop;
+use metrics::MetricsServer;
+use progress::{Bar, Goal, Progress};
+use request_log::RequestLog;
+use sampler::{Sample, Sampler};
+use stats::{Counters, WorkerStats};
+use statsd::StatsdPusher;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use template::RequestSource;
+use thiserror::Error;
+use tui::Dashboard;
+
+/// What stops a test before or while it starts. Failed requests don't, they
+/// are counted in the results.
+#[derive(Debug, Error)]
+pub enum RunError {
+    #[error("could not read payload {}: {source}", path.display())]
+    Payload { path: PathBuf, source: io::Error },
+    #[error("{0}")]
+    GraphqlPayload(String),
+    #[error("WS_MESSAGE_FILE is not UTF-8 text; use WS_MESSAGE_TYPE=binary to send it as is")]
+    WsMessageNotText,
+    #[error("data file {}: {reason}", path.display())]
+    DataFile { path: PathBuf, reason: String },
+    #[error("invalid template in {0}")]
+    Template(String),
+    #[error("could not create request log {}: {source}", path.display())]
+    RequestLog { path: PathBuf, source: io::Error },
+    #[error("OAuth2 token request failed: {0}")]
+    OAuth2(String),
+    #[error("GraphQL introspection failed: {0}")]
+    Introspection(String),
+    #[error("DNS lookup failed: {0}")]
+    Lookup(String),
+    #[error("request failed: {0}")]
+    Preflight(String),
+    #[error("can't serve metrics on port {port}: {source}")]
+    Metrics { port: u16, source: io::Error },
+    #[error("can't push metrics to StatsD at {host}:{port}: {source}")]
+    Statsd {
+        host: String,
+        port: u16,
+        source: io::Error,
+    },
+    #[error("can't build the HTTP client: {0}")]
+    Client(reqwest::Error),
+}
+
+/// Everything a finished test measured.
+pub struct TestResult {
+    pub summary: Summary,
+    /// The merged measurements of all workers the summary was made of.
+    pub stats: WorkerStats,
+    /// Throughput once a second, only taken with REPORT_FILE.
+    pub samples: Vec<Sample>,
+}
+
+/// What a dry run found, without a test being run.
+pub struct DryRun {
+    /// The target hosts that were looked up.
+    pub lookups: Vec<engine::Lookup>,
+    pub preflight: engine::Preflight,
+    /// Why the target isn't ready for the test, `None` if it responded with
+    /// a success status and a body that passed the checks.
+    pub problem: Option<String>,
+}
+
+/// Loads the payload, data and templates of `config` and runs the test.
+pub fn run(config: Config) -> Result<TestResult, RunError> {
+    LoadTest::prepare(config)?.run()
+}
+
+/// A test with everything it sends loaded and checked, ready to run.
+pub struct LoadTest {
+    config: Config,
+    source: Arc<RequestSource>,
+    request_log: Option<Arc<RequestLog>>,
+    stop: Stop,
+}
+
+impl LoadTest {
+    /// Reads the files the requests are made of, compiles the templates and
+    /// opens the request log, warning about settings that look like a
+    /// mistake. Nothing is sent yet.
+    pub fn prepare(config: Config) -> Result<LoadTest, RunError> {
+        // A configured payload must be readable; without one no body is sent
+        let payload_path = config.payload_path.as_ref();
+        let payload = match (
+            payload_path.or(config.ws_message_file.as_ref()),
+            &config.graphql_query_file,
+        ) {
+            (Some(path), _) => Some(fs::read(path).map_err(|source| RunError::Payload {
+                path: path.clone(),
+                source,
+            })?),
+            (None, Some(query)) => Some(
+                graphql::payload(query, config.graphql_variables_file.as_deref())
+                    .map_err(RunError::GraphqlPayload)?,
+            ),
+            (None, None) => None,
+        };
+        if config.protocol == Protocol::Ws
+            && config.ws_message_type == WsMessageType::Text
+            && payload
+                .as_ref()
+                .is_some_and(|message| std::str::from_utf8(message).is_err())
+        {
+            return Err(RunError::WsMessageNotText);
+        }
+        let data = match &config.data_file {
+            Some(path) => Some(DataFeed::load(path).map_err(|reason| RunError::DataFile {
+                path: path.clone(),
+                reason,
+            })?),
+            None => None,
+        };
+        // Templates are compiled and tried out before anything is sent
+        let source =
+            Arc::new(RequestSource::new(&config, payload, data).map_err(RunError::Template)?);
+        warn_about(&config, &source);
+        let request_log = match &config.request_log {
+            Some(path) => {
+                let payloads: Vec<&str> = (config.payload_files.iter())
+                    .map(|file| file.name.as_str())
+                    .collect();
+                let log =
+                    RequestLog::open(path, &config.target_urls, &payloads).map_err(|source| {
+                        RunError::RequestLog {
+                            path: path.clone(),
+                            source,
+                        }
+                    })?;
+                Some(Arc::new(log))
+            }
+            None => None,
+        };
+        Ok(LoadTest {
+            config,
+            source,
+            request_log,
+            stop: Stop::default(),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn source(&self) -> &RequestSource {
+        &self.source
+    }
+
+    /// Handle to end the run early, from another thread or a signal handler;
+    /// the workers finish their in-flight requests and the results cover
+    /// what completed.
+    pub fn stop_handle(&self) -> Stop {
+        self.stop.clone()
+    }
+
+    /// The CSV log of REQUEST_LOG, for closing it when the process has to
+    /// exit early.
+    pub fn request_log(&self) -> Option<&Arc<RequestLog>> {
+        self.request_log.as_ref()
+    }
+
+    /// Checks the setup instead of running the test: looks up the target
+    /// hosts and sends the first request once, see [`engine::preflight`].
+    /// Nothing is counted, written to the request log or pushed as metrics.
+    pub fn dry_run(self) -> Result<DryRun, RunError> {
+        let lookups = engine::lookup(&self.config).map_err(RunError::Lookup)?;
+        let config = Arc::new(self.config);
+        let _oauth2 = start_oauth2(&config)?;
+        let preflight =
+            engine::preflight(Arc::clone(&config), self.source).map_err(RunError::Preflight)?;
+        let problem = if !config.success_codes.contains(preflight.status.as_u16()) {
+            Some(format!(
+                "{} is not a success status (SUCCESS_STATUS_CODES)",
+                preflight.status
+            ))
+        } else {
+            (preflight.invalid_body.as_ref())
+                .map(|reason| format!("response failed validation: {}", reason))
+        };
+        Ok(DryRun {
+            lookups,
+            preflight,
+            problem,
+        })
+    }
+
+    /// Runs the warmup, if any, and the test, with the live outputs the
+    /// config asks for, until every worker is done or a stop is requested
+    /// through `stop_handle`.
+    pub fn run(self) -> Result<TestResult, RunError> {
+        let LoadTest {
+            config,
+            source,
+            request_log,
+            stop,
+        } = self;
+        let config = Arc::new(config);
+
+        // Every request needs the token, the introspection query included;
+        // it's refreshed until the run returns
+        let _oauth2 = start_oauth2(&config)?;
+
+        if config.graphql_introspect {
+            let schema = graphql::introspect(&config).map_err(RunError::Introspection)?;
+            if config.verbosity > Verbosity::Quiet {
+                say!(
+                    "GraphQL schema: {} types (query: {}, mutation: {})",
+                    schema.types,
+                    schema.query_type.as_deref().unwrap_or("none"),
+                    schema.mutation_type.as_deref().unwrap_or("none")
+                );
+            }
+        }
+
+        let counters = Arc::new(Counters::new(config.num_threads));
+
+        // Up before the warmup, so that scrapes see the whole run
+        let metrics = match config.metrics_port {
+            Some(port) => {
+                let metrics = MetricsServer::start(Arc::clone(&counters), port)
+                    .map_err(|source| RunError::Metrics { port, source })?;
+                if config.verbosity > Verbosity::Quiet {
+                    say!("Metrics: http://0.0.0.0:{}/metrics", port);
+                }
+                Some(metrics)
+            }
+            None => None,
+        };
+        let statsd = match &config.statsd {
+            Some(statsd) => {
+                let pusher =
+                    StatsdPusher::start(Arc::clone(&counters), statsd).map_err(|source| {
+                        RunError::Statsd {
+                            host: statsd.host.clone(),
+                            port: statsd.port,
+                            source,
+                        }
+                    })?;
+                if config.verbosity > Verbosity::Quiet {
+                    say!(
+                        "StatsD: {}:{} ({}.*)",
+                        statsd.host,
+                        statsd.port,
+                        statsd.prefix
+                    );
+                }
+                Some(pusher)
+            }
+            None => None,
+        };
+
+        let mut warmup_discarded = 0;
+        if config.warms_up() {
+            warmup_discarded = match engine::warmup(
+                Arc::clone(&config),
+                Arc::clone(&source),
+                Arc::clone(&counters),
+                stop.clone(),
+            ) {
+                Ok(discarded) => discarded,
+                Err(err) => {
+                    if let Some(metrics) = metrics {
+                        metrics.finish();
+                    }
+                    if let Some(statsd) = statsd {
+                        statsd.finish();
+                    }
+                    return Err(RunError::Client(err));
+                }
+            };
+            counters.reset();
+            if config.verbosity > Verbosity::Quiet {
+                say!("Warmup complete, starting measured run");
+                say!("----------------------------------------------------------------------");
+            }
+        }
+
+        // Throughput over time, only needed for the report
+        let sampler = config
+            .report
+            .as_ref()
+            .map(|_| Sampler::start(Arc::clone(&counters), Duration::from_secs(1)));
+
+        let goal = match (config.requests_per_thread, config.test_duration) {
+            (Some(requests_per_thread), _) => {
+                Goal::Requests((config.num_threads * requests_per_thread) as u64)
+            }
+            (None, Some(test_duration)) => Goal::Duration(test_duration),
+            (None, None) => unreachable!("either a request count or a duration is configured"),
+        };
+        // The dashboard takes the place of every other live output
+        let dashboard = (config.tui && io::stdout().is_terminal())
+            .then(|| Dashboard::start(Arc::clone(&counters), stop.clone(), goal));
+
+        let progress = config
+            .report_interval
+            .filter(|_| config.verbosity > Verbosity::Quiet && dashboard.is_none())
+            .map(|interval| Progress::start(Arc::clone(&counters), interval));
+
+        // Only for a human watching: piped output gets no bar
+        let bar = (config.progress_bar
+            && config.verbosity > Verbosity::Quiet
+            && dashboard.is_none()
+            && io::stdout().is_terminal()
+            && io::stderr().is_terminal())
+        .then(|| Bar::start(Arc::clone(&counters), goal));
+
+        let started_at = Utc::now();
+        let start = Instant::now();
+
+        let stats = engine::run(
+            Arc::clone(&config),
+            source,
+            Arc::clone(&counters),
+            stop.clone(),
+            request_log,
+        );
+        let elapsed = start.elapsed();
+
+        if let Some(dashboard) = dashboard {
+            dashboard.finish();
+        }
+        if let Some(bar) = bar {
+            bar.finish();
+        }
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+        let samples = sampler.map(Sampler::finish).unwrap_or_default();
+        if let Some(metrics) = metrics {
+            metrics.finish();
+        }
+        if let Some(statsd) = statsd {
+            statsd.finish();
+        }
+        let stats = stats.map_err(RunError::Client)?;
+
+        let summary = Summary::new(
+            &config,
+            &counters,
+            &stats,
+            warmup_discarded,
+            stop.requested(),
+            started_at,
+            elapsed,
+        );
+        Ok(TestResult {
+            summary,
+            stats,
+            samples,
+        })
+    }
+}
+
+/// Gets the first OAUTH2_TOKEN_URL token, if there is one to get. It's kept
+/// fresh until the `Refresher` is dropped.
+fn start_oauth2(config: &Arc<Config>) -> Result<Option<oauth2::Refresher>, RunError> {
+    if config.oauth2.is_none() {
+        return Ok(None);
+    }
+    let refresher = oauth2::start(config).map_err(RunError::OAuth2)?;
+    if config.verbosity > Verbosity::Quiet {
+        match refresher.lifetime {
+            Some(lifetime) => say!("OAuth2 token acquired, expires in {}s", lifetime.as_secs()),
+            None => say!("OAuth2 token acquired"),
+        }
+    }
+    Ok(Some(refresher))
+}
+
+/// Settings that are valid but most likely not what was meant, on stderr
+/// unless quiet.
+fn warn_about(config: &Config, source: &RequestSource) {
+    if config.verbosity == Verbosity::Quiet {
+        return;
+    }
+    if config.data_file.is_some() && !source.is_templated() {
+        esay!("Warning: DATA_FILE is set, but neither the URLs nor the payload use its columns");
+    }
+    // Most likely a typo in the host or port
+    for pinned in &config.resolve {
+        if !config.target_urls.iter().any(|url| pinned.matches(url)) {
+            esay!(
+                "Warning: RESOLVE pins {}:{}, which no target URL goes to",
+                pinned.host,
+                pinned.port
+            );
+        }
+    }
+    // Known before any lookup for addresses given literally or pinned
+    for url in &config.target_urls {
+        // Templated URLs may not parse, nothing is known about those
+        let Ok(parsed) = reqwest::Url::parse(url) else {
+            continue;
+        };
+        let literal = parsed
+            .host_str()
+            .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+            .and_then(|host| host.parse::<IpAddr>().ok());
+        let addrs = match (
+            literal,
+            config.resolve.iter().find(|pinned| pinned.matches(url)),
+        ) {
+            (Some(addr), _) => vec![addr],
+            (None, Some(pinned)) => pinned.addrs.clone(),
+            (None, None) => continue,
+        };
+        if !config.proxied(&parsed) && !addrs.iter().any(|addr| config.ip_version.allows(addr)) {
+            esay!(
+                "Warning: {} has no {} address, its requests will fail",
+                url,
+                config.ip_version
+            );
+        }
+    }
+    if let AuthConfig::TokenFile { path, tokens } = &config.auth {
+        if tokens.len() < config.num_threads {
+            esay!(
+                "Warning: {} has {} tokens for {} threads, some threads share a token",
+                path.display(),
+                tokens.len(),
+                config.num_threads
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::testing::TestServer;
+    use std::collections::BTreeMap;
+
+    /// A quiet config for `threads` threads sending `requests` requests each
+    /// to `url`.
+    fn config(url: &str, threads: usize, requests: usize, use_async: bool) -> Config {
+        Config::try_from(Settings {
+            target_url: Some(url.into()),
+            num_threads: Some(threads),
+            requests_per_thread: Some(requests),
+            use_async: Some(use_async),
+            verbosity: Some(Verbosity::Quiet),
+            ..Settings::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn run_counts_every_request_the_server_got() {
+        for use_async in [false, true] {
+            let server = TestServer::start(200, "ok");
+            let result = run(config(&server.url, 2, 3, use_async)).unwrap();
+            let summary = &result.summary;
+            assert_eq!(server.requests().len(), 6);
+            assert_eq!(summary.total_requests, 6);
+            assert_eq!(summary.success_count, 6);
+            assert_eq!(summary.failure_count, 0);
+            assert_eq!(summary.status_codes, BTreeMap::from([(200, 6)]));
+            assert_eq!(summary.urls[0].success_count, 6);
+            assert!(!summary.interrupted);
+            assert_eq!(result.stats.latency.len(), 6);
+            assert!(result.samples.is_empty(), "only sampled for REPORT_FILE");
+        }
+    }
+
+    #[test]
+    fn run_counts_failure_statuses() {
+        for use_async in [false, true] {
+            let server = TestServer::start(503, "busy");
+            let summary = run(config(&server.url, 1, 4, use_async)).unwrap().summary;
+            assert_eq!(summary.total_requests, 4);
+            assert_eq!(summary.success_count, 0);
+            assert_eq!(summary.failure_count, 4);
+            assert_eq!(summary.status_codes, BTreeMap::from([(503, 4)]));
+            assert!(summary.errors.is_empty(), "all of them got a response");
+        }
+    }
+
+    #[test]
+    fn missing_payload_stops_the_run_before_any_request() {
+        let server = TestServer::start(200, "ok");
+        let config = Config::try_from(Settings {
+            target_url: Some(server.url.clone()),
+            payload_path: Some("no-such-payload.json".into()),
+            verbosity: Some(Verbosity::Quiet),
+            ..Settings::default()
+        })
+        .unwrap();
+        match run(config) {
+            Err(RunError::Payload { path, .. }) => {
+                assert_eq!(path, PathBuf::from("no-such-payload.json"))
+            }
+            other => panic!("expected a payload error, got {:?}", other.err()),
+        }
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn runs_in_one_process_count_apart() {
+        let server = TestServer::start(200, "ok");
+        let first = run(config(&server.url, 2, 3, false)).unwrap().summary;
+        let second = run(config(&server.url, 1, 2, true)).unwrap().summary;
+        assert_eq!((first.total_requests, first.success_count), (6, 6));
+        assert_eq!((second.total_requests, second.success_count), (2, 2));
+        assert_eq!(second.status_codes, BTreeMap::from([(200, 2)]));
+        assert_eq!(server.requests().len(), 8);
+    }
+
+    #[test]
+    fn stopping_a_run_leaves_the_next_one_alone() {
+        let server = TestServer::start(200, "ok");
+        let stopped = LoadTest::prepare(config(&server.url, 1, 5, false)).unwrap();
+        stopped.stop_handle().request();
+        let summary = stopped.run().unwrap().summary;
+        assert!(summary.interrupted);
+        assert_eq!(summary.total_requests, 0);
+
+        let summary = run(config(&server.url, 1, 5, false)).unwrap().summary;
+        assert!(!summary.interrupted);
+        assert_eq!((summary.total_requests, summary.success_count), (5, 5));
+    }
+}
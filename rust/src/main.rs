@@ -1,229 +1,467 @@
+use ::console::Style;
 use dotenv::dotenv;
-use std::env;
-use std::fs;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
+use load_tester_rust::config::{
+    AuthConfig, AuthType, Config, DataMode, FakeLocale, Http2, HttpVersion, IpVersion,
+    MultipartValue, OutputFormat, Verbosity,
+};
+use load_tester_rust::summary::format_rate;
+use load_tester_rust::template::RequestSource;
+use load_tester_rust::{console, esay, report, say, tui, LoadTest, TestResult};
 use std::thread;
-use std::time::Instant;
 
-// These will be loaded from .env or default values
-struct Config {
-    num_threads: usize,
-    requests_per_thread: usize,
-    target_url: String,
-    auth_token: String,
-}
-
-static TOTAL_DURATION_NS: AtomicU64 = AtomicU64::new(0);
-static MIN_DURATION_NS: AtomicU64 = AtomicU64::new(u64::MAX);
-static MAX_DURATION_NS: AtomicU64 = AtomicU64::new(0);
-
-static SUCCESS_COUNT: AtomicUsize = AtomicUsize::new(0);
-static FAILURE_COUNT: AtomicUsize = AtomicUsize::new(0);
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv().ok(); // Load .env file, ignore if not found
 
-fn update_min(val: u64) {
-    loop {
-        let old = MIN_DURATION_NS.load(Ordering::Relaxed);
-        if val >= old {
-            break;
-        }
-        if MIN_DURATION_NS
-            .compare_exchange(old, val, Ordering::Relaxed, Ordering::Relaxed)
-            .is_ok()
-        {
-            break;
+    // CLI flags > environment (.env included) > --config file > defaults
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
         }
+    };
+    // In JSON mode stdout is reserved for the results
+    if config.output_format == OutputFormat::Json {
+        console::redirect_to_stderr();
     }
-}
+    console::init_colors(config.color);
 
-fn update_max(val: u64) {
-    loop {
-        let old = MAX_DURATION_NS.load(Ordering::Relaxed);
-        if val <= old {
-            break;
-        }
-        if MAX_DURATION_NS
-            .compare_exchange(old, val, Ordering::Relaxed, Ordering::Relaxed)
-            .is_ok()
-        {
-            break;
+    let test = match LoadTest::prepare(config) {
+        Ok(test) => test,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
         }
-    }
-}
-
-fn get_env_usize(key: &str, default: usize) -> usize {
-    match env::var(key) {
-        Ok(val_str) => match val_str.parse::<usize>() {
-            Ok(val) => val,
-            Err(_) => {
-                println!(
-                    "Warning: could not parse env var {} as usize: {}. Using default {}",
-                    key, val_str, default
-                );
-                default
+    };
+    let config = test.config();
+    // The first Ctrl+C (or SIGTERM) lets in-flight requests finish and still
+    // reports what completed; a second one quits right away
+    {
+        let log = test.request_log().cloned();
+        let stop = test.stop_handle();
+        ctrlc::set_handler(move || {
+            if stop.request() {
+                tui::restore();
+                if let Some(log) = &log {
+                    log.close_or_warn();
+                }
+                std::process::exit(130);
             }
-        },
-        Err(_) => default,
+            esay!("Interrupted, waiting for in-flight requests (Ctrl+C again to quit now)");
+        })?;
     }
-}
-
-fn get_env_string(key: &str, default: &str) -> String {
-    match env::var(key) {
-        Ok(val) if !val.is_empty() => val,
-        _ => {
-            if default.is_empty() && key != "AUTH_TOKEN" {
-                // Auth token can be empty
-                println!(
-                    "Warning: env var {} is not set and no default value provided",
-                    key
-                );
+    // Hard limit for the whole test, warmup included
+    if let Some(limit) = config.test_timeout {
+        let log = test.request_log().cloned();
+        thread::spawn(move || {
+            thread::sleep(limit);
+            tui::restore();
+            eprintln!(
+                "Error: test did not finish within {}s, aborting",
+                limit.as_secs()
+            );
+            if let Some(log) = log {
+                log.close_or_warn();
             }
-            default.to_string()
-        }
+            std::process::exit(4);
+        });
     }
-}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv().ok(); // Load .env file, ignore if not found
+    if config.verbosity > Verbosity::Quiet {
+        print_banner(config, test.source());
+    }
+    if config.dry_run {
+        dry_run(test);
+    }
+    // Kept for what follows the run, as the test takes its own
+    let json_out = config.json_out.clone();
+    let output_format = config.output_format;
+    let output_file = config.output_file.clone();
+    let report_file = config.report.clone();
+    let max_failure_rate = config.max_failure_rate;
 
-    let config = Config {
-        num_threads: get_env_usize("NUM_THREADS", 20),
-        requests_per_thread: get_env_usize("REQUESTS_PER_THREAD", 50),
-        target_url: get_env_string("TARGET_URL", "http://localhost:3000/api/foo"),
-        auth_token: get_env_string("AUTH_TOKEN", ""),
+    let TestResult {
+        summary,
+        stats,
+        samples,
+    } = match test.run() {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
     };
+    summary.print();
 
-    if config.target_url.is_empty() {
-        eprintln!("Error: TARGET_URL must be set either in .env or as an environment variable.");
-        std::process::exit(1);
+    match (&json_out, output_format) {
+        (Some(path), _) => summary.write_json(path)?,
+        (None, OutputFormat::Json) => println!("{}", summary.to_json()?),
+        (None, OutputFormat::Text) => {}
     }
-
-    let payload = fs::read("payload.json")?;
-
-    println!("🚀 Starting load test (Rust)...");
-    let total_requests = config.num_threads * config.requests_per_thread;
-    println!(
-        "Threads: {}, Requests/Thread: {}, Total: {}",
-        config.num_threads, config.requests_per_thread, total_requests
-    );
-    println!("Target URL: {}", config.target_url);
-    if config.auth_token.is_empty() {
-        println!("Auth Token: Not set");
-    } else {
-        println!("Auth Token: Set (hidden)");
+    if let Some(path) = &output_file {
+        summary.write_file(path, output_format)?;
+        say!("Results written to {}", path.display());
     }
-    println!("----------------------------------------------------------------------");
-
-    let start = Instant::now();
-
-    let payload_arc = Arc::new(payload);
-    let mut handles = Vec::with_capacity(config.num_threads);
-    let config_arc = Arc::new(config); // Share config across threads
-
-    for thread_id in 1..=config_arc.num_threads {
-        let payload_clone = Arc::clone(&payload_arc);
-        let current_config = Arc::clone(&config_arc); // Clone Arc for the thread
-
-        let handle = thread::spawn(move || {
-            let client = reqwest::blocking::Client::builder()
-                .danger_accept_invalid_certs(true) // Consider security implications
-                .build()
-                .expect("failed to build client");
-
-            for i in 0..current_config.requests_per_thread {
-                let req_num = i + 1;
-                let start_req = Instant::now();
-
-                let mut request_builder = client
-                    .post(&current_config.target_url)
-                    .header("Content-Type", "application/json")
-                    .body((*payload_clone).clone());
-
-                if !current_config.auth_token.is_empty() {
-                    request_builder = request_builder.header(
-                        "Authorization",
-                        format!("Bearer {}", current_config.auth_token),
-                    );
-                }
-
-                let res = request_builder.send();
-
-                let dur_ns = start_req.elapsed().as_nanos() as u64;
-
-                TOTAL_DURATION_NS.fetch_add(dur_ns, Ordering::Relaxed);
-                update_min(dur_ns);
-                update_max(dur_ns);
-
-                match res {
-                    Ok(resp) => {
-                        if resp.status() == reqwest::StatusCode::OK
-                            || resp.status() == reqwest::StatusCode::CREATED
-                        {
-                            SUCCESS_COUNT.fetch_add(1, Ordering::Relaxed);
-                        } else {
-                            FAILURE_COUNT.fetch_add(1, Ordering::Relaxed);
-                        }
-                        println!(
-                            "Thread {:>2} | Request {:>3}/{} | Status: {}",
-                            thread_id,
-                            req_num,
-                            current_config.requests_per_thread, // Use config from Arc
-                            resp.status()
-                        );
-                    }
-                    Err(err) => {
-                        FAILURE_COUNT.fetch_add(1, Ordering::Relaxed);
-                        eprintln!(
-                            "Thread {:>2} | Request {:>3}/{} | Error: {}",
-                            thread_id,
-                            req_num,
-                            current_config.requests_per_thread,
-                            err // Use config from Arc
-                        );
-                    }
-                }
-            }
-        });
-        handles.push(handle);
+    if let Some(path) = &report_file {
+        report::write_html(path, &summary, &stats, &samples)?;
+        say!("Report written to {}", path.display());
     }
-
-    for handle in handles {
-        handle.join().expect("thread panicked");
+    if summary.interrupted {
+        std::process::exit(130);
+    }
+    // For pipelines: a run that failed too often fails the build
+    if let Some(max_rate) = max_failure_rate {
+        if summary.total_requests == 0 {
+            eprintln!(
+                "Error: no requests completed, so the maximum failure rate ({}%) can't be met",
+                max_rate * 100.0
+            );
+            std::process::exit(2);
+        }
+        let rate = summary.failure_count as f64 / summary.total_requests as f64;
+        if rate > max_rate {
+            eprintln!(
+                "Error: failure rate {:.2}% ({} of {} requests) exceeds the maximum of {}%",
+                rate * 100.0,
+                summary.failure_count,
+                summary.total_requests,
+                max_rate * 100.0
+            );
+            std::process::exit(2);
+        }
+    }
+    // The error rate was checked above
+    let blown: Vec<String> = (summary.sla.iter())
+        .filter(|sla| !sla.passed && sla.metric != "error_rate_pct")
+        .map(|sla| format!("{} ({})", sla.condition(), sla.actual_text()))
+        .collect();
+    if !blown.is_empty() {
+        eprintln!("Error: SLA violated: {}", blown.join(", "));
+        std::process::exit(3);
+    }
+    if !summary.sla.is_empty() {
+        say!("All SLAs met ✅");
     }
 
-    let duration = start.elapsed();
-    let duration_ms = duration.as_secs_f64() * 1000.0;
+    Ok(())
+}
 
-    let rps = if duration.as_secs_f64() > 0.0 {
-        (total_requests as f64) / duration.as_secs_f64()
-    } else {
-        0.0
+/// Checks the setup with a single request instead of the test, and exits
+/// with 0 if the target is ready for it or 1 if not.
+fn dry_run(test: LoadTest) -> ! {
+    let dry_run = match test.dry_run() {
+        Ok(dry_run) => dry_run,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
     };
+    for lookup in &dry_run.lookups {
+        let addrs: Vec<String> = lookup.addrs.iter().map(ToString::to_string).collect();
+        say!(
+            "DNS: {}:{} -> {}{}",
+            lookup.host,
+            lookup.port,
+            addrs.join(", "),
+            if lookup.pinned { " (RESOLVE)" } else { "" }
+        );
+    }
+    let preflight = &dry_run.preflight;
+    say!(
+        "{} {} -> {} ({}, {:.2} ms)",
+        preflight.method,
+        preflight.url,
+        console::status(preflight.status.as_u16(), preflight.status),
+        preflight.version,
+        preflight.elapsed.as_secs_f64() * 1000.0
+    );
+    for (name, value) in &preflight.headers {
+        say!("  {}: {}", name, String::from_utf8_lossy(value.as_bytes()));
+    }
+    if let Some(problem) = &dry_run.problem {
+        eprintln!("Error: {}", problem);
+        std::process::exit(1);
+    }
+    say!("Dry run passed, the target is ready for the test ✅");
+    std::process::exit(0);
+}
 
-    let avg_ms = if total_requests > 0 {
-        TOTAL_DURATION_NS.load(Ordering::Relaxed) as f64 / total_requests as f64 / 1_000_000.0
+/// What is about to run, before the first request.
+fn print_banner(config: &Config, source: &RequestSource) {
+    if config.dry_run {
+        say!("🔍 Dry run: checking the setup with one request (Rust)...");
     } else {
-        0.0
-    };
-
-    let min_final_ns = MIN_DURATION_NS.load(Ordering::Relaxed);
-    let min_ms = if min_final_ns != u64::MAX {
-        min_final_ns as f64 / 1_000_000.0
+        say!("🚀 Starting load test (Rust)...");
+    }
+    match (config.requests_per_thread, config.test_duration) {
+        (Some(requests_per_thread), Some(test_duration)) => say!(
+            "Threads: {}, Requests/Thread: {}, Total: {} (or {}s, whichever comes first)",
+            config.num_threads,
+            requests_per_thread,
+            config.num_threads * requests_per_thread,
+            test_duration.as_secs()
+        ),
+        (Some(requests_per_thread), None) => say!(
+            "Threads: {}, Requests/Thread: {}, Total: {}",
+            config.num_threads,
+            requests_per_thread,
+            config.num_threads * requests_per_thread
+        ),
+        (None, Some(test_duration)) => say!(
+            "Threads: {}, Duration: {}s",
+            config.num_threads,
+            test_duration.as_secs()
+        ),
+        (None, None) => unreachable!("either a request count or a duration is configured"),
+    }
+    match config.target_urls.as_slice() {
+        urls if !config.scenarios.is_empty() => {
+            say!("Scenarios ({}):", urls.len());
+            for (index, scenario) in config.scenarios.iter().enumerate() {
+                let payload = match scenario.payload {
+                    Some(payload) => format!(", payload {}", config.payload_files[payload].name),
+                    None => String::new(),
+                };
+                say!(
+                    "  - {}: {} {} ({:.1}%{})",
+                    scenario.name,
+                    scenario.method,
+                    urls[index],
+                    config.url_weights[index] * 100.0,
+                    payload
+                );
+            }
+        }
+        [url] => say!("Target URL: {} {}", config.request_label(), url),
+        urls => {
+            say!(
+                "Target URLs ({}): {}",
+                config.url_strategy,
+                config.request_label()
+            );
+            for (url, weight) in urls.iter().zip(&config.url_weights) {
+                say!("  - {} ({:.1}%)", url, weight * 100.0);
+            }
+        }
+    }
+    if let Some(multipart) = &config.multipart {
+        let fields: Vec<String> = (multipart.fields.iter())
+            .map(|field| match &field.value {
+                MultipartValue::Text(_) => field.name.clone(),
+                MultipartValue::File { path, content } => format!(
+                    "{}=@{} ({} bytes)",
+                    field.name,
+                    path.display(),
+                    content.len()
+                ),
+            })
+            .collect();
+        say!("Body: multipart/form-data ({})", fields.join(", "));
+    }
+    if let Some(path) = &config.graphql_query_file {
+        match &config.graphql_variables_file {
+            Some(variables) => say!(
+                "Body: GraphQL {} with variables {}",
+                path.display(),
+                variables.display()
+            ),
+            None => say!("Body: GraphQL {}", path.display()),
+        }
+    }
+    if let Some(path) = &config.ws_message_file {
+        say!(
+            "Message: {} ({} frames)",
+            path.display(),
+            config.ws_message_type
+        );
+    }
+    if let Some(dir) = &config.payload_dir {
+        say!(
+            "Payloads: {} ({} files, {})",
+            dir.display(),
+            config.payload_files.len(),
+            config.payload_strategy
+        );
+    }
+    if let Some(fields) = &config.form_fields {
+        let names: Vec<&str> = fields.iter().map(|(name, _)| name.as_str()).collect();
+        say!("Body: form ({})", names.join(", "));
+    }
+    if !config.query_params.is_empty() {
+        let query: Vec<String> = (config.query_params.iter())
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        say!("Query parameters: {}", query.join("&"));
+    }
+    if let (Some(path), Some(data)) = (&config.data_file, source.data()) {
+        say!(
+            "Data file: {} ({} rows, {}{})",
+            path.display(),
+            data.rows().len(),
+            config.data_strategy,
+            match config.data_mode {
+                DataMode::Stop => ", stop after the last row",
+                DataMode::Cycle => "",
+            }
+        );
+    }
+    if source.is_templated() && config.fake_locale != FakeLocale::EnUs {
+        say!("Fake data locale: {}", config.fake_locale);
+    }
+    if let Some(ramp_up) = config.ramp_up {
+        say!("Ramp-up: {}s", ramp_up.as_secs());
+    }
+    match (config.warmup_requests, config.warmup_duration) {
+        (0, None) => {}
+        (0, Some(duration)) => say!("Warmup: {}s", duration.as_secs()),
+        (requests, None) => say!("Warmup: {} requests", requests),
+        (requests, Some(duration)) => say!(
+            "Warmup: {} requests or {}s, whichever comes first",
+            requests,
+            duration.as_secs()
+        ),
+    }
+    if !config.think_time_jitter.is_zero() {
+        say!(
+            "Think time: {}-{} ms",
+            config.think_time.as_millis(),
+            (config.think_time + config.think_time_jitter).as_millis()
+        );
+    } else if !config.think_time.is_zero() {
+        say!("Think time: {} ms", config.think_time.as_millis());
+    }
+    if let Some(target_rps) = config.target_rps {
+        say!("Target RPS: {}", format_rate(target_rps));
+    }
+    if config.use_async {
+        say!("Engine: async (Tokio tasks)");
     } else {
-        0.0
-    };
-    let max_ms = MAX_DURATION_NS.load(Ordering::Relaxed) as f64 / 1_000_000.0;
-
-    println!("----------------------------------------------------------------------");
-    println!("✅ Test completed in {:.2} ms", duration_ms);
-    println!("Total requests: {}", total_requests);
-    println!("  -> Success ✅: {}", SUCCESS_COUNT.load(Ordering::Relaxed));
-    println!("  -> Failure ❌: {}", FAILURE_COUNT.load(Ordering::Relaxed));
-    println!("Performance: ~{:.2} requests/second (RPS)", rps);
-    println!(
-        "Response times (ms): min {:.2} | avg {:.2} | max {:.2}",
-        min_ms, avg_ms, max_ms
-    );
-
-    Ok(())
+        say!("Engine: blocking (OS threads)");
+    }
+    if let Some(path) = &config.tls.client_cert_path {
+        say!("Client certificate: {}", path.display());
+    }
+    if let Some(path) = &config.tls.ca_cert_path {
+        say!(
+            "Trusted CA: {} ({} certificates)",
+            path.display(),
+            config.tls.ca_certs.len()
+        );
+    }
+    if config.tls.accept_invalid_certs {
+        // On stderr, so that it isn't lost when stdout is piped
+        esay!(
+            "{}",
+            Style::new().yellow().bold().apply_to(
+                "⚠️ TLS certificate verification: DISABLED, any certificate is accepted, \
+                 including one for the wrong host or from an intercepting proxy"
+            )
+        );
+    }
+    match config.http2 {
+        Some(Http2::PriorKnowledge) => say!("Protocol: HTTP/2 (prior knowledge)"),
+        Some(Http2::Alpn) => say!("Protocol: HTTP/2 (ALPN, HTTP/1.1 fallback)"),
+        None if config.http_version == HttpVersion::Http1 => say!("Protocol: HTTP/1.1 only"),
+        None => {}
+    }
+    if !config.connection_reuse {
+        say!("Connections: new one per request (no keep-alive)");
+    }
+    if config.connection_reuse
+        && (config.pool_max_idle_per_host.is_some()
+            || config.pool_idle_timeout.is_some()
+            || config.shared_client)
+    {
+        let max_idle = match config.pool_max_idle_per_host {
+            Some(max_idle) => max_idle.to_string(),
+            None => "no limit".to_string(),
+        };
+        let idle_timeout = match config.pool_idle_timeout {
+            Some(timeout) => format!("{}s", timeout.as_secs()),
+            None => "90s".to_string(),
+        };
+        let clients = match config.shared_client || config.use_async {
+            true => "one client for all workers",
+            false => "one client per worker",
+        };
+        say!(
+            "Connection pool: up to {} idle per host, closed after {} idle ({})",
+            max_idle,
+            idle_timeout,
+            clients
+        );
+    }
+    if config.ip_version != IpVersion::Any {
+        say!("IP version: {} only", config.ip_version);
+    }
+    match config.local_addrs.as_slice() {
+        [] => {}
+        [addr] => say!("Local address: {}", addr),
+        addrs => {
+            let addrs: Vec<String> = addrs.iter().map(ToString::to_string).collect();
+            say!(
+                "Local addresses: {} (one per thread, in turn)",
+                addrs.join(", ")
+            );
+        }
+    }
+    for pinned in &config.resolve {
+        let addrs: Vec<String> = pinned.addrs.iter().map(ToString::to_string).collect();
+        say!(
+            "DNS override: {}:{} -> {}",
+            pinned.host,
+            pinned.port,
+            addrs.join(", ")
+        );
+    }
+    if let Some(proxy_url) = &config.proxy_url {
+        if config.no_proxy.is_empty() {
+            say!("Proxy: {}", proxy_url);
+        } else {
+            say!(
+                "Proxy: {} (except {})",
+                proxy_url,
+                config.no_proxy.join(", ")
+            );
+        }
+    }
+    match (&config.login, config.cookie_jar) {
+        (Some(login), _) => say!("Session: cookie jar per thread, login at {}", login.url),
+        (None, true) => say!("Session: cookie jar per thread"),
+        (None, false) => {}
+    }
+    if let Some(signer) = &config.signer {
+        say!(
+            "Signing: HMAC-SHA256 in {}, with X-Timestamp",
+            signer.header()
+        );
+    }
+    match (&config.auth, &config.oauth2) {
+        (AuthConfig::Basic { username, .. }, _) => say!("Auth: Basic (user: {})", username),
+        (AuthConfig::None, _) => say!("Auth: None"),
+        (AuthConfig::Bearer(_), Some(oauth2)) => say!(
+            "Auth Token: OAuth2 client credentials from {}",
+            oauth2.token_url
+        ),
+        (AuthConfig::Bearer(token), None) if token.get().is_none() => {
+            say!("Auth Token: Not set")
+        }
+        (AuthConfig::Bearer(_), None) => say!("Auth Token: Set (hidden)"),
+        (AuthConfig::TokenFile { path, tokens }, _) => say!(
+            "Auth Token: {} tokens from {}, one per thread",
+            tokens.len(),
+            path.display()
+        ),
+    }
+    if config.auth.auth_type() == AuthType::Bearer && !config.token_header.is_default() {
+        say!(
+            "Auth Header: {}: {}",
+            config.token_header.name,
+            config.token_header.value("<token>")
+        );
+    }
+    if let Some(refresh) = &config.token_refresh {
+        say!("Token refresh: on 401, from {}", refresh.url);
+    }
+    say!("----------------------------------------------------------------------");
 }
@@ -0,0 +1,184 @@
+use crate::stats::{Activity, Counters, LATENCY_BUCKETS, LATENCY_BUCKET_US};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the listener checks whether it should stop.
+const POLL: Duration = Duration::from_millis(100);
+/// Scrapers that don't send their request within this are dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Background thread serving the live counters on `/metrics` in the
+/// Prometheus text format, one scrape at a time.
+pub struct MetricsServer {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub fn start(counters: Arc<Counters>, port: u16) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+        // Polled, so that finish doesn't wait for one more scrape
+        listener.set_nonblocking(true)?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let started = Instant::now();
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        // A scraper that went away is its own problem
+                        let _ = serve(stream, &counters, started);
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        // Woken up early (or orphaned) means the run is over
+                        if let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(POLL) {
+                            continue;
+                        }
+                        break;
+                    }
+                    Err(_) => thread::sleep(POLL),
+                }
+            }
+        });
+        Ok(MetricsServer { stop, handle })
+    }
+
+    pub fn finish(self) {
+        let _ = self.stop.send(());
+        self.handle.join().expect("metrics thread panicked");
+    }
+}
+
+/// Answers a single request, with the metrics for `GET /metrics` and 404
+/// for anything else.
+fn serve(stream: TcpStream, counters: &Counters, started: Instant) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers don't matter, but are read so the client sees a clean close
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            render(counters, started),
+        ),
+        _ => (
+            "404 Not Found",
+            "text/plain",
+            "Not found, try /metrics\n".into(),
+        ),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// The counters in the Prometheus text exposition format.
+fn render(counters: &Counters, started: Instant) -> String {
+    let counted = counters.snapshot();
+    let mut text = String::new();
+
+    let _ = writeln!(
+        text,
+        "# HELP load_tester_requests_total Completed requests by outcome.\n\
+         # TYPE load_tester_requests_total counter\n\
+         load_tester_requests_total{{status=\"success\"}} {}\n\
+         load_tester_requests_total{{status=\"failure\"}} {}",
+        counted.success, counted.failure
+    );
+    let _ = writeln!(
+        text,
+        "# HELP load_tester_validation_failures_total Failed requests whose body didn't pass validation.\n\
+         # TYPE load_tester_validation_failures_total counter\n\
+         load_tester_validation_failures_total {}",
+        counted.validation_failure
+    );
+    let _ = writeln!(
+        text,
+        "# HELP load_tester_errors_total Failed requests other than validation, by category.\n\
+         # TYPE load_tester_errors_total counter\n\
+         load_tester_errors_total{{category=\"http\"}} {}\n\
+         load_tester_errors_total{{category=\"connection\"}} {}\n\
+         load_tester_errors_total{{category=\"timeout\"}} {}",
+        counted.http_error, counted.conn_error, counted.timeout_error
+    );
+
+    let _ = writeln!(
+        text,
+        "# HELP load_tester_latency_seconds Total time of the completed requests.\n\
+         # TYPE load_tester_latency_seconds histogram"
+    );
+    let mut cumulative = 0;
+    // The last bucket takes everything slower, which +Inf covers
+    for (bucket, count) in counted.latency_buckets[..LATENCY_BUCKETS - 1]
+        .iter()
+        .enumerate()
+    {
+        cumulative += count;
+        let le = (bucket as u64 + 1) * LATENCY_BUCKET_US;
+        let _ = writeln!(
+            text,
+            "load_tester_latency_seconds_bucket{{le=\"{}\"}} {}",
+            le as f64 / 1e6,
+            cumulative
+        );
+    }
+    // Summed apart from the buckets, so it may be a request ahead of them
+    let count = cumulative + counted.latency_buckets[LATENCY_BUCKETS - 1];
+    let _ = writeln!(
+        text,
+        "load_tester_latency_seconds_bucket{{le=\"+Inf\"}} {}\n\
+         load_tester_latency_seconds_sum {}\n\
+         load_tester_latency_seconds_count {}",
+        count,
+        counted.latency_us as f64 / 1e6,
+        count
+    );
+
+    let activity = counters.activity();
+    let _ = writeln!(
+        text,
+        "# HELP load_tester_threads Worker threads by what they're doing.\n\
+         # TYPE load_tester_threads gauge"
+    );
+    for (state, label) in [
+        (Activity::NotStarted, "not_started"),
+        (Activity::Sending, "sending"),
+        (Activity::Waiting, "waiting"),
+        (Activity::Done, "done"),
+    ] {
+        let _ = writeln!(
+            text,
+            "load_tester_threads{{state=\"{}\"}} {}",
+            label,
+            activity.iter().filter(|&&a| a == state).count()
+        );
+    }
+
+    let _ = writeln!(
+        text,
+        "# HELP load_tester_elapsed_seconds Time since the metrics endpoint started.\n\
+         # TYPE load_tester_elapsed_seconds gauge\n\
+         load_tester_elapsed_seconds {:.3}",
+        started.elapsed().as_secs_f64()
+    );
+    text
+}
@@ -0,0 +1,222 @@
+use crate::config::{Config, OAuth2, Verbosity};
+use crate::engine;
+use serde::Deserialize;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long before it expires an access token is replaced.
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// Wait before another attempt after a failed refresh.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Bearer token sent with every request, empty for none. Cloned handles share
+/// it, so a refreshed OAuth2 token reaches every worker mid-run.
+#[derive(Clone)]
+pub struct Token(Arc<RwLock<String>>);
+
+impl Token {
+    pub fn new(token: String) -> Token {
+        Token(Arc::new(RwLock::new(token)))
+    }
+
+    /// The token to send right now, `None` without one.
+    pub fn get(&self) -> Option<String> {
+        let token = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        (!token.is_empty()).then(|| token.clone())
+    }
+
+    pub fn set(&self, token: String) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = token;
+    }
+}
+
+/// The OAuth2 token of a run. The thread replacing it before it expires
+/// stops when this is dropped, so that it doesn't outlive the run.
+pub struct Refresher {
+    /// Lifetime of the first token, `None` if it doesn't expire.
+    pub lifetime: Option<Duration>,
+    thread: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+        if let Some((stop, handle)) = self.thread.take() {
+            let _ = stop.send(());
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Successful token response (RFC 6749, section 5.1).
+#[derive(Deserialize)]
+struct Grant {
+    access_token: String,
+    /// Lifetime in seconds; some servers send it as a string.
+    #[serde(default, deserialize_with = "lifetime")]
+    expires_in: Option<u64>,
+}
+
+/// Error response (RFC 6749, section 5.2).
+#[derive(Deserialize)]
+struct GrantError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Gets a first access token with the client credentials grant and makes it
+/// the bearer token of `config.auth`. If it expires, a background thread gets
+/// a new one `REFRESH_MARGIN` before, which workers pick up with their next
+/// request, until the returned `Refresher` is dropped.
+pub fn start(config: &Arc<Config>) -> Result<Refresher, String> {
+    let oauth2 = config.oauth2.as_ref().expect("OAuth2 is configured");
+    let grant = request_token(config, oauth2)?;
+    let lifetime = grant.expires_in.map(Duration::from_secs);
+    token(config).set(grant.access_token);
+
+    let thread = lifetime.map(|lifetime| {
+        let config = Arc::clone(config);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || refresh(&config, lifetime, &stopped));
+        (stop, handle)
+    });
+    Ok(Refresher { lifetime, thread })
+}
+
+/// Replaces the token shortly before it expires, until `stopped`. A failed
+/// refresh keeps the old token and is retried.
+fn refresh(config: &Config, lifetime: Duration, stopped: &Receiver<()>) {
+    let oauth2 = config.oauth2.as_ref().expect("OAuth2 is configured");
+    let mut wait = lifetime.saturating_sub(REFRESH_MARGIN).max(RETRY_DELAY);
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+        match request_token(config, oauth2) {
+            Ok(grant) => {
+                token(config).set(grant.access_token);
+                if config.verbosity >= Verbosity::Verbose {
+                    say!("OAuth2 token refreshed");
+                }
+                match grant.expires_in {
+                    Some(lifetime) => {
+                        wait = Duration::from_secs(lifetime)
+                            .saturating_sub(REFRESH_MARGIN)
+                            .max(RETRY_DELAY);
+                    }
+                    None => return,
+                }
+            }
+            Err(err) => {
+                if config.verbosity >= Verbosity::Normal {
+                    esay!(
+                        "OAuth2 token refresh failed, retrying in {}s: {}",
+                        RETRY_DELAY.as_secs(),
+                        err
+                    );
+                }
+                wait = RETRY_DELAY;
+            }
+        }
+    }
+}
+
+fn token(config: &Config) -> &Token {
+    (config.auth.shared_token())
+        .expect("OAuth2 is only allowed with AUTH_TYPE=bearer and no AUTH_TOKEN_FILE")
+}
+
+/// POSTs the client credentials to the token endpoint, with the connection
+/// settings of the requests under test.
+fn request_token(config: &Config, oauth2: &OAuth2) -> Result<Grant, String> {
+    let mut form = vec![
+        ("grant_type", "client_credentials"),
+        ("client_id", oauth2.client_id.as_str()),
+        ("client_secret", oauth2.client_secret.as_str()),
+    ];
+    if let Some(scope) = &oauth2.scope {
+        form.push(("scope", scope));
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build Tokio runtime");
+    let (status, text) = runtime.block_on(async {
+        let response = engine::async_client(config, config.local_address(1))
+            .map_err(|err| err.to_string())?
+            .post(&oauth2.token_url)
+            .header("Accept", "application/json")
+            .form(&form)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = response.status();
+        let text = response.text().await.map_err(|err| err.to_string())?;
+        Ok::<_, String>((status, text))
+    })?;
+
+    if !status.is_success() {
+        return Err(match serde_json::from_str::<GrantError>(&text) {
+            Ok(GrantError {
+                error,
+                error_description: Some(description),
+            }) => format!("status {}, {}: {}", status, error, description),
+            Ok(GrantError { error, .. }) => format!("status {}, {}", status, error),
+            // Not an OAuth2 error, the body may still tell what went wrong
+            Err(_) if !text.trim().is_empty() => format!("status {}: {}", status, text.trim()),
+            Err(_) => format!("status {}", status),
+        });
+    }
+    serde_json::from_str(&text).map_err(|err| {
+        format!(
+            "status {}, no access_token in the response: {}",
+            status, err
+        )
+    })
+}
+
+/// `expires_in` as a number or a numeric string.
+fn lifetime<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Seconds {
+        Number(u64),
+        Text(String),
+    }
+    Ok(match Option::<Seconds>::deserialize(deserializer)? {
+        Some(Seconds::Number(seconds)) => Some(seconds),
+        Some(Seconds::Text(text)) => text.trim().parse().ok(),
+        None => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::testing::TestServer;
+
+    #[test]
+    fn the_refresh_thread_ends_with_the_run() {
+        let server = TestServer::start(200, r#"{"access_token":"abc","expires_in":3600}"#);
+        let config = Arc::new(
+            Config::try_from(Settings {
+                target_url: Some(server.url.clone()),
+                oauth2_token_url: Some(server.url.clone()),
+                oauth2_client_id: Some("id".into()),
+                oauth2_client_secret: Some("secret".into()),
+                verbosity: Some(Verbosity::Quiet),
+                ..Settings::default()
+            })
+            .unwrap(),
+        );
+        let refresher = start(&config).unwrap();
+        assert_eq!(refresher.lifetime, Some(Duration::from_secs(3600)));
+        assert_eq!(token(&config).get().as_deref(), Some("abc"));
+        assert_eq!(Arc::strong_count(&config), 2, "held by the refresh thread");
+
+        drop(refresher);
+        assert_eq!(Arc::strong_count(&config), 1, "the refresh thread is gone");
+        assert_eq!(server.requests().len(), 1);
+    }
+}
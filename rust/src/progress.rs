@@ -0,0 +1,149 @@
+use crate::console;
+use crate::sampler::Sample;
+use crate::stats::Counters;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Background thread printing a one-line snapshot of the run every
+/// `interval`, with the rate and average latency of that interval alone.
+pub struct Progress {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Progress {
+    pub fn start(counters: Arc<Counters>, interval: Duration) -> Progress {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let started = Instant::now();
+            let mut last = Sample::take(&counters);
+            // Woken up early (or orphaned) means the run is over
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let sample = Sample::take(&counters);
+                print_line(started.elapsed(), &last, &sample);
+                last = sample;
+            }
+        });
+        Progress { stop, handle }
+    }
+
+    pub fn finish(self) {
+        // A failed send means the thread is gone already, which join reports
+        let _ = self.stop.send(());
+        self.handle.join().expect("progress thread panicked");
+    }
+}
+
+/// What the progress bar fills up with.
+#[derive(Clone, Copy)]
+pub enum Goal {
+    /// Count-based runs: completed requests out of this many, with an ETA.
+    Requests(u64),
+    /// Duration-based runs: elapsed time out of this much.
+    Duration(Duration),
+}
+
+/// Live progress bar at the bottom of the terminal, with the RPS and average
+/// latency of the last second. Other output goes above it while it's shown,
+/// see `console`.
+pub struct Bar {
+    bar: ProgressBar,
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Bar {
+    pub fn start(counters: Arc<Counters>, goal: Goal) -> Bar {
+        const TICK: Duration = Duration::from_millis(100);
+        const TICKS_PER_RATE: u32 = 10;
+
+        let (bar, template) = match goal {
+            Goal::Requests(total) => (
+                ProgressBar::new(total),
+                "{bar:30} {percent:>3}% | {pos}/{len} requests | {elapsed} | {msg} | ETA {eta}",
+            ),
+            Goal::Duration(duration) => (
+                ProgressBar::new(duration.as_millis() as u64)
+                    .with_prefix(format!("{}s", duration.as_secs())),
+                "{bar:30} {percent:>3}% | {elapsed} / {prefix} | {msg}",
+            ),
+        };
+        let style = ProgressStyle::with_template(template).expect("valid progress bar template");
+        let bar = bar.with_style(style).with_message("0.00 RPS, avg 0.00 ms");
+        console::show_bar(bar.clone());
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn({
+            let bar = bar.clone();
+            move || {
+                let started = Instant::now();
+                let mut last = (started, 0, 0);
+                let mut ticks = 0;
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(TICK) {
+                    let now = Instant::now();
+                    let snapshot = counters.snapshot();
+                    let done = snapshot.completed();
+                    bar.set_position(match goal {
+                        Goal::Requests(_) => done,
+                        Goal::Duration(_) => (now - started).as_millis() as u64,
+                    });
+                    ticks += 1;
+                    if ticks % TICKS_PER_RATE == 0 {
+                        let latency_us = snapshot.latency_us;
+                        let seconds = (now - last.0).as_secs_f64();
+                        let requests = done - last.1;
+                        let avg_ms = match requests {
+                            0 => 0.0,
+                            _ => (latency_us - last.2) as f64 / requests as f64 / 1000.0,
+                        };
+                        bar.set_message(format!(
+                            "{:.2} RPS, avg {:.2} ms",
+                            requests as f64 / seconds,
+                            avg_ms
+                        ));
+                        last = (now, done, latency_us);
+                    }
+                }
+            }
+        });
+        Bar { bar, stop, handle }
+    }
+
+    /// Stops updating and erases the bar, so the summary starts on a clean
+    /// line.
+    pub fn finish(self) {
+        let _ = self.stop.send(());
+        self.handle.join().expect("progress bar thread panicked");
+        self.bar.finish_and_clear();
+        console::hide_bar();
+    }
+}
+
+fn print_line(elapsed: Duration, last: &Sample, sample: &Sample) {
+    let total = sample.success + sample.failure;
+    let requests = total - (last.success + last.failure);
+    let seconds = (sample.timestamp - last.timestamp).num_milliseconds() as f64 / 1000.0;
+    let rps = if seconds > 0.0 {
+        requests as f64 / seconds
+    } else {
+        0.0
+    };
+    let avg_ms = if requests > 0 {
+        (sample.latency_us - last.latency_us) as f64 / requests as f64 / 1000.0
+    } else {
+        0.0
+    };
+    say!(
+        "[{:>8.3}s] Progress | {} requests (Success: {}, Failure: {}) | last {:.0}s: {:.2} RPS, avg {:.2} ms",
+        elapsed.as_secs_f64(),
+        total,
+        sample.success,
+        sample.failure,
+        seconds,
+        rps,
+        avg_ms
+    );
+}
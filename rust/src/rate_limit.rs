@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Global rate limiter shared by every worker.
+///
+/// Instead of refilling a token counter on a timer, the bucket hands out send
+/// slots spaced `1 / rate` apart: each call reserves the next free slot and
+/// the caller waits until it comes up. The rate therefore holds across all
+/// workers together and does not drift when individual requests are slow
+/// (a slow worker simply leaves its slot to whoever asks next). Idle time
+/// does not accumulate, so there are no bursts above the target rate.
+pub struct TokenBucket {
+    /// Set by the first reservation, so slots don't expire while workers are
+    /// still starting up.
+    start: OnceLock<Instant>,
+    interval_ns: u64,
+    next_slot_ns: AtomicU64,
+}
+
+impl TokenBucket {
+    pub fn new(requests_per_second: f64) -> TokenBucket {
+        TokenBucket {
+            start: OnceLock::new(),
+            interval_ns: (1_000_000_000.0 / requests_per_second) as u64,
+            next_slot_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserves the next send slot and returns how long to wait for it.
+    pub fn reserve(&self) -> Duration {
+        let now_ns = self.start.get_or_init(Instant::now).elapsed().as_nanos() as u64;
+        let mut next_ns = self.next_slot_ns.load(Ordering::Relaxed);
+        loop {
+            let slot_ns = next_ns.max(now_ns);
+            match self.next_slot_ns.compare_exchange_weak(
+                next_ns,
+                slot_ns + self.interval_ns,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Duration::from_nanos(slot_ns - now_ns),
+                Err(actual) => next_ns = actual,
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::sampler::Sample;
+use crate::stats::WorkerStats;
+use crate::summary::Summary;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Page layout, styling and charts; the run data is spliced in at `DATA`.
+const TEMPLATE: &str = include_str!("report.html");
+const DATA_PLACEHOLDER: &str = "/*DATA*/null";
+
+/// Number of equal-width bars in the latency histogram.
+const HISTOGRAM_BINS: u64 = 40;
+
+/// Everything the page renders, embedded as a JSON literal.
+#[derive(Serialize)]
+struct ReportData<'a> {
+    summary: &'a Summary,
+    histogram: Vec<Bin>,
+    samples: &'a [Sample],
+}
+
+#[derive(Serialize)]
+struct Bin {
+    from_ms: f64,
+    to_ms: f64,
+    count: u64,
+}
+
+/// Writes a self-contained HTML report (no external resources) of the run.
+pub fn write_html(
+    path: &Path,
+    summary: &Summary,
+    stats: &WorkerStats,
+    samples: &[Sample],
+) -> io::Result<()> {
+    let data = ReportData {
+        summary,
+        histogram: latency_bins(stats),
+        samples,
+    };
+    // `</` would end the inline script early, `\/` is the same JSON string
+    let json = serde_json::to_string(&data)?.replace("</", "<\\/");
+    fs::write(path, TEMPLATE.replace(DATA_PLACEHOLDER, &json))
+}
+
+/// Spreads the recorded latencies over `HISTOGRAM_BINS` equal-width bins
+/// between the min and the max.
+fn latency_bins(stats: &WorkerStats) -> Vec<Bin> {
+    let histogram = &stats.latency;
+    if histogram.is_empty() {
+        return Vec::new();
+    }
+    let (min, max) = (histogram.min(), histogram.max());
+    // Rounded up so the last bin still covers the max
+    let width = (max - min) / HISTOGRAM_BINS + 1;
+
+    let mut bins: Vec<Bin> = (0..HISTOGRAM_BINS)
+        .map(|i| Bin {
+            from_ms: (min + i * width) as f64 / 1_000.0,
+            to_ms: (min + (i + 1) * width) as f64 / 1_000.0,
+            count: 0,
+        })
+        .collect();
+    for value in histogram.iter_recorded() {
+        let us = histogram.median_equivalent(value.value_iterated_to());
+        let index = (us.saturating_sub(min) / width).min(HISTOGRAM_BINS - 1);
+        bins[index as usize].count += value.count_at_value();
+    }
+    // Narrow ranges leave empty bins past the max
+    while bins.last().is_some_and(|bin| bin.count == 0) {
+        bins.pop();
+    }
+    bins
+}
@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Longest time a written row may sit in the buffer.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// CSV file with one row per request, shared by all workers.
+///
+/// Workers only push rows into a channel; a dedicated writer thread owns the
+/// file, so disk I/O never adds to the measured latency. Rows are buffered
+/// and flushed at least every `FLUSH_INTERVAL`.
+pub struct RequestLog {
+    rows: Sender<Message>,
+    writer: Mutex<Option<JoinHandle<io::Result<()>>>>,
+}
+
+enum Message {
+    Row(Row),
+    Close,
+}
+
+struct Row {
+    thread_id: usize,
+    request_num: usize,
+    /// Index in the URL list the log was opened with.
+    url: usize,
+    /// Index in the payload file list, with one.
+    payload: Option<usize>,
+    start_epoch_ms: u128,
+    duration_ns: u128,
+    /// The response status and/or the error kind (transport error or
+    /// `validation`).
+    status_code: Option<u16>,
+    error: Option<&'static str>,
+}
+
+impl RequestLog {
+    /// Creates (or truncates) `path`, writes the header row and starts the
+    /// writer thread. `urls` are the target URLs rows refer to by index, and
+    /// `payloads` the PAYLOAD_DIR file names; a `payload` column is only
+    /// written with some.
+    pub fn open(path: &Path, urls: &[String], payloads: &[&str]) -> io::Result<RequestLog> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(
+            out,
+            "thread_id,request_num,url,start_epoch_ms,duration_ns,status_code,error"
+        )?;
+        if payloads.is_empty() {
+            writeln!(out)?;
+        } else {
+            writeln!(out, ",payload")?;
+        }
+
+        let urls: Vec<String> = urls.iter().map(|url| csv_field(url)).collect();
+        let payloads: Vec<String> = payloads.iter().map(|name| csv_field(name)).collect();
+        let (rows, received) = mpsc::channel();
+        let writer = thread::spawn(move || write_rows(out, &urls, &payloads, received));
+
+        Ok(RequestLog {
+            rows,
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// Queues a row for a request to the `url`th URL with the `payload`th
+    /// payload file that just finished after `elapsed`.
+    pub fn record(
+        &self,
+        thread_id: usize,
+        request_num: usize,
+        (url, payload): (usize, Option<usize>),
+        status_code: Option<u16>,
+        error: Option<&'static str>,
+        elapsed: Duration,
+    ) {
+        let start = SystemTime::now() - elapsed;
+        let row = Row {
+            thread_id,
+            request_num,
+            url,
+            payload,
+            start_epoch_ms: start
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            duration_ns: elapsed.as_nanos(),
+            status_code,
+            error,
+        };
+        // Only fails once closed or after a write error, which close() reports
+        let _ = self.rows.send(Message::Row(row));
+    }
+
+    /// Writes out every queued row and closes the file. Rows recorded
+    /// afterwards are dropped; closing again is a no-op.
+    pub fn close(&self) -> io::Result<()> {
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+        match writer {
+            Some(writer) => {
+                let _ = self.rows.send(Message::Close);
+                writer.join().expect("request log writer panicked")
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// `close` for the end of a run, where a write error is only worth a warning.
+    pub fn close_or_warn(&self) {
+        if let Err(err) = self.close() {
+            eprintln!("Warning: could not write request log: {}", err);
+        }
+    }
+}
+
+/// Body of the writer thread, runs until `Message::Close`.
+fn write_rows(
+    mut out: BufWriter<File>,
+    urls: &[String],
+    payloads: &[String],
+    rows: Receiver<Message>,
+) -> io::Result<()> {
+    let mut flushed_at = Instant::now();
+    loop {
+        match rows.recv_timeout(FLUSH_INTERVAL.saturating_sub(flushed_at.elapsed())) {
+            Ok(Message::Row(row)) => {
+                write!(
+                    out,
+                    "{},{},{},{},{},{},{}",
+                    row.thread_id,
+                    row.request_num,
+                    urls[row.url],
+                    row.start_epoch_ms,
+                    row.duration_ns,
+                    row.status_code
+                        .map(|code| code.to_string())
+                        .unwrap_or_default(),
+                    row.error.unwrap_or_default()
+                )?;
+                match row.payload {
+                    Some(payload) => writeln!(out, ",{}", payloads[payload])?,
+                    None if payloads.is_empty() => writeln!(out)?,
+                    None => writeln!(out, ",")?,
+                }
+            }
+            Ok(Message::Close) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        if flushed_at.elapsed() >= FLUSH_INTERVAL {
+            out.flush()?;
+            flushed_at = Instant::now();
+        }
+    }
+    out.flush()
+}
+
+/// Quotes `value` if it contains a CSV delimiter, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,79 @@
+use crate::stats::Counters;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Snapshot of the shared counters at one point of the run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    pub timestamp: DateTime<Utc>,
+    pub success: u64,
+    pub failure: u64,
+    /// Sum of the latencies recorded so far.
+    pub latency_us: u64,
+}
+
+impl Sample {
+    pub fn take(counters: &Counters) -> Sample {
+        let now = counters.snapshot();
+        Sample {
+            timestamp: Utc::now(),
+            success: now.success,
+            failure: now.failure,
+            latency_us: now.latency_us,
+        }
+    }
+}
+
+/// Background thread snapshotting the counters every `interval`, from which
+/// the throughput over time is derived.
+pub struct Sampler {
+    samples: Arc<Mutex<Vec<Sample>>>,
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Sampler {
+    /// Takes a first sample right away, then one per `interval`.
+    pub fn start(counters: Arc<Counters>, interval: Duration) -> Sampler {
+        let samples = Arc::new(Mutex::new(vec![Sample::take(&counters)]));
+        let (stop, stopped) = mpsc::channel();
+
+        let handle = {
+            let samples = Arc::clone(&samples);
+            thread::spawn(move || loop {
+                // Woken up early (or orphaned) means the run is over
+                let stop = !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                let sample = Sample::take(&counters);
+                samples
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .push(sample);
+                if stop {
+                    break;
+                }
+            })
+        };
+
+        Sampler {
+            samples,
+            stop,
+            handle,
+        }
+    }
+
+    /// Takes a last sample and returns all of them in order.
+    pub fn finish(self) -> Vec<Sample> {
+        // A failed send means the thread is gone already, which join reports
+        let _ = self.stop.send(());
+        self.handle.join().expect("sampler thread panicked");
+        let mut samples = self.samples.lock().unwrap_or_else(|err| err.into_inner());
+        std::mem::take(&mut *samples)
+    }
+}
@@ -0,0 +1,71 @@
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Header with the Unix time the signature covers.
+const TIMESTAMP_HEADER: &str = "X-Timestamp";
+
+/// Signs requests with HMAC-SHA256 over
+/// `method \n url \n hex(sha256(body)) \n timestamp`, so the target can check
+/// they come from a holder of the shared secret and reject replays.
+pub struct Signer {
+    secret: Vec<u8>,
+    header: HeaderName,
+}
+
+impl Signer {
+    pub fn new(secret: &str, header: HeaderName) -> Signer {
+        Signer {
+            secret: secret.as_bytes().to_vec(),
+            header,
+        }
+    }
+
+    /// Name of the header carrying the hex signature.
+    pub fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    /// Timestamp and signature headers of a request sent now.
+    pub fn headers(&self, method: &str, url: &str, body: &[u8]) -> HeaderMap {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(
+            format!(
+                "{}\n{}\n{}\n{}",
+                method,
+                url,
+                hex(&Sha256::digest(body)),
+                timestamp
+            )
+            .as_bytes(),
+        );
+        let signature = hex(&mac.finalize().into_bytes());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TIMESTAMP_HEADER,
+            HeaderValue::from_str(&timestamp).expect("digits are a valid header value"),
+        );
+        headers.insert(
+            self.header.clone(),
+            HeaderValue::from_str(&signature).expect("hex is a valid header value"),
+        );
+        headers
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(text, "{:02x}", byte);
+    }
+    text
+}
@@ -0,0 +1,473 @@
+use hdrhistogram::Histogram;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+/// Width of the latency buckets the dashboard draws, in microseconds.
+pub const LATENCY_BUCKET_US: u64 = 10_000;
+/// Number of latency buckets, the last one taking everything slower.
+pub const LATENCY_BUCKETS: usize = 20;
+
+/// Why a request failed, unless it was the validation of its body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The server responded, with a status that isn't a success.
+    Http,
+    /// No response, for any reason but a timeout.
+    Connection,
+    /// No response or connection in time.
+    Timeout,
+}
+
+impl ErrorCategory {
+    /// Category of a failure without a response of the given error kind.
+    pub fn of_kind(kind: &str) -> ErrorCategory {
+        match kind {
+            "timeout" | "connect_timeout" => ErrorCategory::Timeout,
+            _ => ErrorCategory::Connection,
+        }
+    }
+}
+
+/// What a worker is up to, for the dashboard.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    NotStarted,
+    /// A request is in flight.
+    Sending,
+    /// Between two requests: thinking, paced or backing off.
+    Waiting,
+    Done,
+}
+
+impl Activity {
+    fn from_u8(value: u8) -> Activity {
+        match value {
+            1 => Activity::Sending,
+            2 => Activity::Waiting,
+            3 => Activity::Done,
+            _ => Activity::NotStarted,
+        }
+    }
+}
+
+/// Live request outcome counters, for the outputs that follow a run while it
+/// goes. Every worker counts in a slot of its own, so that workers never
+/// write to the same cache line; `snapshot` adds them up.
+pub struct Counters {
+    /// Indexed by thread ID - 1.
+    slots: Vec<Slot>,
+    /// How many times the counters started over.
+    generation: AtomicU64,
+}
+
+/// The counters of one worker, aligned so that no two share a cache line
+/// (or the pair of lines some CPUs prefetch together).
+#[derive(Default)]
+#[repr(align(128))]
+struct Slot {
+    success: AtomicU64,
+    failure: AtomicU64,
+    validation_failure: AtomicU64,
+    http_error: AtomicU64,
+    conn_error: AtomicU64,
+    timeout_error: AtomicU64,
+    latency_us: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS],
+    activity: AtomicU8,
+}
+
+/// The counters of every worker added up, at about one point in time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Snapshot {
+    pub success: u64,
+    pub failure: u64,
+    /// Failures with a success status whose body didn't pass validation.
+    pub validation_failure: u64,
+    /// The other failures by category, see `ErrorCategory`; with the
+    /// validation failures they add up to `failure`.
+    pub http_error: u64,
+    pub conn_error: u64,
+    pub timeout_error: u64,
+    /// Sum of the latencies of every completed request, for averages over
+    /// part of the run.
+    pub latency_us: u64,
+    /// Completed requests per `LATENCY_BUCKET_US` wide latency bucket.
+    pub latency_buckets: [u64; LATENCY_BUCKETS],
+    /// Bumped by every `Counters::reset`: snapshots of different
+    /// generations can't be subtracted.
+    pub generation: u64,
+}
+
+impl Snapshot {
+    /// Requests completed either way.
+    pub fn completed(&self) -> u64 {
+        self.success + self.failure
+    }
+}
+
+impl Counters {
+    pub fn new(num_threads: usize) -> Counters {
+        Counters {
+            slots: (0..num_threads).map(|_| Slot::default()).collect(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn slot(&self, thread_id: usize) -> &Slot {
+        &self.slots[thread_id - 1]
+    }
+
+    pub fn record_success(&self, thread_id: usize) {
+        self.slot(thread_id).success.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a success status whose body didn't pass validation.
+    pub fn record_validation_failure(&self, thread_id: usize) {
+        let slot = self.slot(thread_id);
+        slot.failure.fetch_add(1, Ordering::Relaxed);
+        slot.validation_failure.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a failure other than validation.
+    pub fn record_error(&self, thread_id: usize, category: ErrorCategory) {
+        let slot = self.slot(thread_id);
+        slot.failure.fetch_add(1, Ordering::Relaxed);
+        let counter = match category {
+            ErrorCategory::Http => &slot.http_error,
+            ErrorCategory::Connection => &slot.conn_error,
+            ErrorCategory::Timeout => &slot.timeout_error,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_latency(&self, thread_id: usize, latency_us: u64) {
+        let slot = self.slot(thread_id);
+        slot.latency_us.fetch_add(latency_us, Ordering::Relaxed);
+        let bucket = ((latency_us / LATENCY_BUCKET_US) as usize).min(LATENCY_BUCKETS - 1);
+        slot.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_activity(&self, thread_id: usize, activity: Activity) {
+        self.slot(thread_id)
+            .activity
+            .store(activity as u8, Ordering::Relaxed);
+    }
+
+    pub fn activity(&self) -> Vec<Activity> {
+        (self.slots.iter())
+            .map(|slot| Activity::from_u8(slot.activity.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Adds up the counters of every worker. Workers keep counting while
+    /// this reads, so the totals may be a request apart from each other.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut total = Snapshot {
+            generation: self.generation.load(Ordering::Relaxed),
+            ..Snapshot::default()
+        };
+        for slot in &self.slots {
+            total.success += slot.success.load(Ordering::Relaxed);
+            total.failure += slot.failure.load(Ordering::Relaxed);
+            total.validation_failure += slot.validation_failure.load(Ordering::Relaxed);
+            total.http_error += slot.http_error.load(Ordering::Relaxed);
+            total.conn_error += slot.conn_error.load(Ordering::Relaxed);
+            total.timeout_error += slot.timeout_error.load(Ordering::Relaxed);
+            total.latency_us += slot.latency_us.load(Ordering::Relaxed);
+            for (sum, bucket) in total.latency_buckets.iter_mut().zip(&slot.latency_buckets) {
+                *sum += bucket.load(Ordering::Relaxed);
+            }
+        }
+        total
+    }
+
+    pub fn reset(&self) {
+        for slot in &self.slots {
+            slot.success.store(0, Ordering::Relaxed);
+            slot.failure.store(0, Ordering::Relaxed);
+            slot.validation_failure.store(0, Ordering::Relaxed);
+            slot.http_error.store(0, Ordering::Relaxed);
+            slot.conn_error.store(0, Ordering::Relaxed);
+            slot.timeout_error.store(0, Ordering::Relaxed);
+            slot.latency_us.store(0, Ordering::Relaxed);
+            for bucket in &slot.latency_buckets {
+                bucket.store(0, Ordering::Relaxed);
+            }
+            slot.activity
+                .store(Activity::NotStarted as u8, Ordering::Relaxed);
+        }
+        // Last, so that a snapshot of the new generation counts from zero
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Measurements owned by a single worker, merged once every worker finished.
+pub struct WorkerStats {
+    /// Total time of every request, body included.
+    pub latency: Histogram<u64>,
+    /// Time until the response headers came in, of the HTTP requests that
+    /// got a response.
+    pub ttfb: Histogram<u64>,
+    /// Responses received per HTTP status code.
+    pub status_codes: BTreeMap<u16, u64>,
+    /// Transport errors per kind (timeout, connect, ...).
+    pub errors: BTreeMap<&'static str, u64>,
+    /// Failed requests per normalized error message, see `error_message`.
+    pub error_messages: HashMap<String, u64>,
+    /// Outcomes per index in `config.target_urls`.
+    pub per_url: BTreeMap<usize, UrlStats>,
+    /// Outcomes per index in `config.payload_files`, empty without
+    /// PAYLOAD_DIR.
+    pub per_payload: BTreeMap<usize, UrlStats>,
+    /// Responses per negotiated HTTP version.
+    pub http_versions: BTreeMap<&'static str, u64>,
+    /// Responses reached through at least one redirect.
+    pub redirected: u64,
+    /// Local address of every connection a response came over.
+    pub connections: HashSet<SocketAddr>,
+    /// Retries sent after transport errors.
+    pub retries: u64,
+    /// Requests that succeeded after at least one retry.
+    pub recovered: u64,
+    /// Token refreshes after a 401 sent by this worker, and how many failed.
+    pub token_refreshes: u64,
+    pub failed_token_refreshes: u64,
+    /// Requests that succeeded when sent again with a refreshed token.
+    pub recovered_after_refresh: u64,
+    /// Outcomes of the LOGIN_URL requests, one per worker.
+    pub logins: u64,
+    pub failed_logins: u64,
+    /// Request and response body bytes, retries included.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Rate once every worker had started; set by the engine on the merged
+    /// stats of a run with ramp-up.
+    pub steady_state_rps: Option<f64>,
+}
+
+impl Default for WorkerStats {
+    fn default() -> WorkerStats {
+        WorkerStats::new()
+    }
+}
+
+impl WorkerStats {
+    pub fn new() -> WorkerStats {
+        WorkerStats {
+            latency: new_histogram(),
+            ttfb: new_histogram(),
+            status_codes: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            error_messages: HashMap::new(),
+            per_url: BTreeMap::new(),
+            per_payload: BTreeMap::new(),
+            http_versions: BTreeMap::new(),
+            redirected: 0,
+            connections: HashSet::new(),
+            retries: 0,
+            recovered: 0,
+            token_refreshes: 0,
+            failed_token_refreshes: 0,
+            recovered_after_refresh: 0,
+            logins: 0,
+            failed_logins: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            steady_state_rps: None,
+        }
+    }
+
+    pub fn merge(&mut self, other: &WorkerStats) {
+        self.latency
+            .add(&other.latency)
+            .expect("histograms share the same bounds");
+        self.ttfb
+            .add(&other.ttfb)
+            .expect("histograms share the same bounds");
+        for (code, count) in &other.status_codes {
+            *self.status_codes.entry(*code).or_insert(0) += count;
+        }
+        for (kind, count) in &other.errors {
+            *self.errors.entry(kind).or_insert(0) += count;
+        }
+        for (message, count) in &other.error_messages {
+            *self.error_messages.entry(message.clone()).or_insert(0) += count;
+        }
+        for (url, stats) in &other.per_url {
+            self.per_url.entry(*url).or_default().merge(stats);
+        }
+        for (payload, stats) in &other.per_payload {
+            self.per_payload.entry(*payload).or_default().merge(stats);
+        }
+        for (version, count) in &other.http_versions {
+            *self.http_versions.entry(version).or_insert(0) += count;
+        }
+        self.redirected += other.redirected;
+        self.connections.extend(&other.connections);
+        self.retries += other.retries;
+        self.recovered += other.recovered;
+        self.token_refreshes += other.token_refreshes;
+        self.failed_token_refreshes += other.failed_token_refreshes;
+        self.recovered_after_refresh += other.recovered_after_refresh;
+        self.logins += other.logins;
+        self.failed_logins += other.failed_logins;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+
+    /// Counts the outcome of a request to the `url`th target URL that sent
+    /// the `payload`th file of PAYLOAD_DIR.
+    pub fn count(&mut self, url: usize, payload: Option<usize>, success: bool) {
+        let url_stats = self.per_url.entry(url).or_default();
+        let payload_stats = payload.map(|payload| self.per_payload.entry(payload).or_default());
+        for stats in std::iter::once(url_stats).chain(payload_stats) {
+            if success {
+                stats.success += 1;
+            } else {
+                stats.failure += 1;
+            }
+        }
+    }
+
+    /// Counts the message of a failed request, with URLs and IDs replaced so
+    /// that the same error on different requests is counted together.
+    pub fn error_message(&mut self, message: &str) {
+        *self.error_messages.entry(normalize(message)).or_insert(0) += 1;
+    }
+}
+
+/// `message` with every URL replaced by `<url>`, and numbers of 4 or more
+/// digits and hex IDs (UUIDs included) by `<id>`.
+fn normalize(message: &str) -> String {
+    let words: Vec<String> = message
+        .split(' ')
+        .map(|word| {
+            // Kept around the replacement, e.g. "(<url>):"
+            let punctuation = |c: char| "()[]{}<>'\",;:.".contains(c);
+            let start = word.len() - word.trim_start_matches(punctuation).len();
+            let inner = word[start..].trim_end_matches(punctuation);
+            let is_url = inner.contains("://");
+            let is_id = inner.len() >= 4
+                && inner.chars().any(|c| c.is_ascii_digit())
+                && inner.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+                && (inner.len() >= 8 || inner.chars().all(|c| c.is_ascii_digit()));
+            match (is_url, is_id) {
+                (true, _) => format!("{}<url>{}", &word[..start], &word[start + inner.len()..]),
+                (_, true) => format!("{}<id>{}", &word[..start], &word[start + inner.len()..]),
+                _ => word.to_string(),
+            }
+        })
+        .collect();
+    words.join(" ")
+}
+
+/// Outcomes and latencies of the requests to one target URL, or with one
+/// payload file.
+pub struct UrlStats {
+    pub success: u64,
+    pub failure: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub latency: Histogram<u64>,
+}
+
+impl UrlStats {
+    fn merge(&mut self, other: &UrlStats) {
+        self.success += other.success;
+        self.failure += other.failure;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.latency
+            .add(&other.latency)
+            .expect("histograms share the same bounds");
+    }
+}
+
+impl Default for UrlStats {
+    fn default() -> UrlStats {
+        UrlStats {
+            success: 0,
+            failure: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            latency: new_histogram(),
+        }
+    }
+}
+
+/// Highest trackable latency (60s); slower requests are clamped to it.
+const HISTOGRAM_MAX_US: u64 = 60 * 1_000_000;
+
+/// Latency histogram with microsecond resolution and 3 significant digits,
+/// i.e. every reported value is within 0.1% of the recorded one.
+pub fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, HISTOGRAM_MAX_US, 3).expect("valid histogram bounds")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Instant;
+
+    const BENCH_THREADS: usize = 64;
+    const BENCH_RECORDS: u64 = 200_000;
+
+    /// Records per second with every thread counting a success and a latency
+    /// per record, each in the slot `slot_of` gives it.
+    fn records_per_sec(slot_of: fn(usize) -> usize) -> f64 {
+        let counters = Counters::new(BENCH_THREADS);
+        let barrier = Barrier::new(BENCH_THREADS);
+        // Timed by the workers: with fewer cores than workers, this thread
+        // may not get to run again before they're done
+        let spans: Vec<(Instant, Instant)> = thread::scope(|scope| {
+            let handles: Vec<_> = (1..=BENCH_THREADS)
+                .map(|thread_id| {
+                    let (counters, barrier) = (&counters, &barrier);
+                    scope.spawn(move || {
+                        let slot = slot_of(thread_id);
+                        barrier.wait();
+                        let start = Instant::now();
+                        for record in 0..BENCH_RECORDS {
+                            counters.record_success(slot);
+                            counters.record_latency(slot, record % 5_000);
+                        }
+                        (start, Instant::now())
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("thread panicked"))
+                .collect()
+        });
+        let first_start = spans.iter().map(|span| span.0).min().expect("workers");
+        let last_end = spans.iter().map(|span| span.1).max().expect("workers");
+        let elapsed = last_end - first_start;
+        assert_eq!(
+            counters.snapshot().success,
+            BENCH_THREADS as u64 * BENCH_RECORDS
+        );
+        (BENCH_THREADS as u64 * BENCH_RECORDS) as f64 / elapsed.as_secs_f64()
+    }
+
+    /// Slots of their own against every worker counting in the one slot, as
+    /// shared counters would. Only tells anything with cores to spare:
+    /// `cargo test --release --lib -- --ignored --nocapture counters_scale`
+    #[test]
+    #[ignore]
+    fn counters_scale_with_a_slot_per_worker() {
+        let own = records_per_sec(|thread_id| thread_id);
+        let shared = records_per_sec(|_| 1);
+        println!(
+            "{} threads on {} CPUs: {:.1}M records/s in slots of their own, {:.1}M in one shared slot ({:.1}x)",
+            BENCH_THREADS,
+            thread::available_parallelism().map_or(1, |n| n.get()),
+            own / 1e6,
+            shared / 1e6,
+            own / shared
+        );
+    }
+}
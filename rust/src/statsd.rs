@@ -0,0 +1,246 @@
+use crate::config::Statsd;
+use crate::stats::{Activity, Counters};
+use std::io;
+use std::net::UdpSocket;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the counters are pushed.
+const INTERVAL: Duration = Duration::from_secs(1);
+/// Largest datagram sent, below the 1432 bytes StatsD suggests for the internet.
+const MAX_DATAGRAM: usize = 1400;
+
+/// Background thread pushing the live counters to a StatsD server over UDP,
+/// once per `INTERVAL` and once more when the run is over.
+pub struct StatsdPusher {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl StatsdPusher {
+    pub fn start(counters: Arc<Counters>, statsd: &Statsd) -> io::Result<StatsdPusher> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        // Resolves the host once, and makes a typo fail the run upfront
+        socket.connect((statsd.host.as_str(), statsd.port))?;
+        let prefix = statsd.prefix.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut last = Snapshot::default();
+            let mut pushed = Instant::now();
+            loop {
+                let done = !matches!(
+                    stopped.recv_timeout(INTERVAL),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                let now = Snapshot::take(&counters);
+                let lines = lines(&prefix, &last, &now, pushed.elapsed());
+                pushed = Instant::now();
+                last = now;
+                // Nobody listening is the server's problem, StatsD is fire and forget
+                for datagram in datagrams(&lines) {
+                    let _ = socket.send(datagram.as_bytes());
+                }
+                if done {
+                    break;
+                }
+            }
+        });
+        Ok(StatsdPusher { stop, handle })
+    }
+
+    pub fn finish(self) {
+        let _ = self.stop.send(());
+        self.handle.join().expect("statsd thread panicked");
+    }
+}
+
+/// The counters at one point of the run.
+#[derive(Default)]
+struct Snapshot {
+    success: u64,
+    failure: u64,
+    validation_failure: u64,
+    http_error: u64,
+    conn_error: u64,
+    timeout_error: u64,
+    latency_us: u64,
+    active: usize,
+    generation: u64,
+}
+
+impl Snapshot {
+    fn take(counters: &Counters) -> Snapshot {
+        let now = counters.snapshot();
+        Snapshot {
+            success: now.success,
+            failure: now.failure,
+            validation_failure: now.validation_failure,
+            http_error: now.http_error,
+            conn_error: now.conn_error,
+            timeout_error: now.timeout_error,
+            latency_us: now.latency_us,
+            active: (counters.activity().iter())
+                .filter(|&&a| a == Activity::Sending || a == Activity::Waiting)
+                .count(),
+            generation: now.generation,
+        }
+    }
+}
+
+/// Counted since `last`.
+fn delta(now: u64, last: u64) -> u64 {
+    now.saturating_sub(last)
+}
+
+/// The StatsD lines for what happened between two snapshots. The counters
+/// start over after the warmup, then everything counted so far is new.
+fn lines(prefix: &str, last: &Snapshot, now: &Snapshot, elapsed: Duration) -> Vec<String> {
+    let started_over = Snapshot::default();
+    let last = if last.generation == now.generation {
+        last
+    } else {
+        &started_over
+    };
+    let success = delta(now.success, last.success);
+    let failure = delta(now.failure, last.failure);
+    let completed = success + failure;
+    let mut lines = vec![
+        format!("{}.requests.success:{}|c", prefix, success),
+        format!("{}.requests.failure:{}|c", prefix, failure),
+        format!(
+            "{}.requests.validation_failure:{}|c",
+            prefix,
+            delta(now.validation_failure, last.validation_failure)
+        ),
+        format!(
+            "{}.requests.http_error:{}|c",
+            prefix,
+            delta(now.http_error, last.http_error)
+        ),
+        format!(
+            "{}.requests.conn_error:{}|c",
+            prefix,
+            delta(now.conn_error, last.conn_error)
+        ),
+        format!(
+            "{}.requests.timeout_error:{}|c",
+            prefix,
+            delta(now.timeout_error, last.timeout_error)
+        ),
+        format!(
+            "{}.requests.rate:{:.2}|g",
+            prefix,
+            completed as f64 / elapsed.as_secs_f64().max(1e-3)
+        ),
+        format!("{}.threads.active:{}|g", prefix, now.active),
+    ];
+    // No requests, no average; a 0 would read as a very fast interval
+    if completed > 0 {
+        let latency_us = delta(now.latency_us, last.latency_us);
+        lines.push(format!(
+            "{}.latency.avg:{:.3}|ms",
+            prefix,
+            latency_us as f64 / completed as f64 / 1000.0
+        ));
+    }
+    lines
+}
+
+/// Packs the lines into newline separated datagrams of at most
+/// `MAX_DATAGRAM` bytes.
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(success: u64, latency_us: u64, generation: u64) -> Snapshot {
+        Snapshot {
+            success,
+            latency_us,
+            generation,
+            ..Snapshot::default()
+        }
+    }
+
+    fn counter<'a>(lines: &'a [String], name: &str) -> &'a str {
+        let prefix = format!("lt.{}:", name);
+        let line = lines
+            .iter()
+            .find(|line| line.starts_with(&prefix))
+            .unwrap_or_else(|| panic!("no {} in {:?}", name, lines));
+        &line[prefix.len()..]
+    }
+
+    #[test]
+    fn lines_count_what_happened_since_the_last_push() {
+        let lines = lines(
+            "lt",
+            &snapshot(50, 50_000, 0),
+            &snapshot(120, 190_000, 0),
+            Duration::from_secs(2),
+        );
+        assert_eq!(counter(&lines, "requests.success"), "70|c");
+        assert_eq!(counter(&lines, "requests.rate"), "35.00|g");
+        assert_eq!(counter(&lines, "latency.avg"), "2.000|ms");
+    }
+
+    #[test]
+    fn lines_count_from_zero_after_the_counters_start_over() {
+        // 50 warmup requests, then 120 measured ones before the next push
+        let lines = lines(
+            "lt",
+            &snapshot(50, 50_000, 0),
+            &snapshot(120, 240_000, 1),
+            Duration::from_secs(1),
+        );
+        assert_eq!(counter(&lines, "requests.success"), "120|c");
+        assert_eq!(counter(&lines, "latency.avg"), "2.000|ms");
+    }
+
+    #[test]
+    fn lines_leave_out_the_latency_without_requests() {
+        let lines = lines(
+            "lt",
+            &snapshot(5, 100, 0),
+            &snapshot(5, 100, 0),
+            Duration::from_secs(1),
+        );
+        assert_eq!(counter(&lines, "requests.success"), "0|c");
+        assert!(!lines.iter().any(|line| line.contains("latency")));
+    }
+
+    #[test]
+    fn datagrams_split_between_lines_below_the_limit() {
+        let lines: Vec<String> = (0..100)
+            .map(|i| format!("lt.{:03}:{}|c", i, "1".repeat(40)))
+            .collect();
+        let packed = datagrams(&lines);
+        assert!(packed.len() > 1);
+        assert!(packed.iter().all(|datagram| datagram.len() <= MAX_DATAGRAM));
+        let rejoined: Vec<&str> = packed
+            .iter()
+            .flat_map(|datagram| datagram.lines())
+            .collect();
+        assert_eq!(rejoined, lines);
+        assert!(datagrams(&[]).is_empty());
+    }
+}
@@ -0,0 +1,493 @@
+use crate::config::{Config, DataMode, FakeLocale, PickStrategy};
+use crate::data::{DataFeed, Row};
+use fake::faker::impls::address::CityNameGenFn;
+use fake::locales::{self, Data};
+use fake::Fake;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tera::{Context, Tera, Value};
+
+/// Everything the URL and body of a request are made from: the target URLs,
+/// the payloads, the data file rows and the templates filling them in.
+pub struct RequestSource {
+    /// Target URLs, with the query parameters already appended unless the
+    /// URL or any parameter is a template.
+    urls: Vec<String>,
+    query: Vec<(String, String)>,
+    /// The payload file, or the files of PAYLOAD_DIR; empty without a body.
+    payloads: Vec<Vec<u8>>,
+    payload_strategy: PickStrategy,
+    /// Payloads handed out so far, for round-robin.
+    payload_cursor: AtomicUsize,
+    data: Option<DataFeed>,
+    data_strategy: PickStrategy,
+    data_mode: DataMode,
+    ids: Ids,
+    templates: Templates,
+}
+
+/// Per-request values of a template.
+pub struct Vars<'a> {
+    pub thread_id: usize,
+    /// 1-based count of the requests of this thread.
+    pub request_num: usize,
+    pub row: Option<&'a Row>,
+    /// Index of the payload to send, from `pick_payload` or the scenario;
+    /// `None` sends no body.
+    pub payload: Option<usize>,
+}
+
+impl RequestSource {
+    /// Compiles the target URLs, query parameter values and the payloads that
+    /// contain template tags (`{{ ... }}`, `{% ... %}`) and renders each once
+    /// per data row (or once without data), so bad syntax, unknown functions
+    /// and variables missing from the data file fail here rather than
+    /// mid-run.
+    pub fn new(
+        config: &Config,
+        payload: Option<Vec<u8>>,
+        data: Option<DataFeed>,
+    ) -> Result<RequestSource, String> {
+        let urls = &config.target_urls;
+        let mut templates = Templates {
+            tera: Tera::default(),
+            urls: vec![false; urls.len()],
+            query: vec![false; config.query_params.len()],
+            payloads: Vec::new(),
+        };
+        templates.tera.register_function("uuid", uuid);
+        templates.tera.register_function("rand_int", rand_int);
+        register_fakers(&mut templates.tera, config.fake_locale);
+        for (index, url) in urls.iter().enumerate() {
+            if is_template(url) {
+                templates
+                    .tera
+                    .add_raw_template(&url_name(index), &named_args(url))
+                    .map_err(|err| format!("URL {}: {}", url, describe(&err)))?;
+                templates.urls[index] = true;
+            }
+        }
+        for (index, (key, value)) in config.query_params.iter().enumerate() {
+            if is_template(value) {
+                templates
+                    .tera
+                    .add_raw_template(&query_name(index), &named_args(value))
+                    .map_err(|err| format!("query parameter {}: {}", key, describe(&err)))?;
+                templates.query[index] = true;
+            }
+        }
+        let payloads: Vec<Vec<u8>> = match payload {
+            Some(payload) => vec![payload],
+            None => (config.payload_files.iter())
+                .map(|file| file.content.clone())
+                .collect(),
+        };
+        let payload_label = |index: usize| match config.payload_files.get(index) {
+            Some(file) => format!("payload {}", file.name),
+            None => "payload".to_string(),
+        };
+        // Binary payloads and JSON without tags are sent as they are
+        for (index, payload) in payloads.iter().enumerate() {
+            let text = std::str::from_utf8(payload)
+                .ok()
+                .filter(|text| is_template(text));
+            if let Some(text) = text {
+                templates
+                    .tera
+                    .add_raw_template(&payload_name(index), &named_args(text))
+                    .map_err(|err| format!("{}: {}", payload_label(index), describe(&err)))?;
+            }
+            templates.payloads.push(text.is_some());
+        }
+
+        let rows: Vec<Option<&Row>> = match &data {
+            Some(data) => data.rows().iter().map(Some).collect(),
+            None => vec![None],
+        };
+        for (index, row) in rows.into_iter().enumerate() {
+            let vars = Vars {
+                thread_id: 1,
+                request_num: 1,
+                row,
+                payload: Some(0),
+            };
+            let context = templates.context(&vars, config.path_param_start, 1);
+            let fail = |what: &str, err: tera::Error| match row {
+                Some(_) => format!("{} with data row {}: {}", what, index + 1, describe(&err)),
+                None => format!("{}: {}", what, describe(&err)),
+            };
+            let mut query = Vec::with_capacity(config.query_params.len());
+            for (index, (key, value)) in config.query_params.iter().enumerate() {
+                query.push(match templates.query[index] {
+                    true => templates
+                        .tera
+                        .render(&query_name(index), &context)
+                        .map_err(|err| fail(&format!("query parameter {}", key), err))?,
+                    false => value.clone(),
+                });
+            }
+            for (url, templated) in templates.urls.iter().enumerate() {
+                let target = match templated {
+                    true => templates
+                        .tera
+                        .render(&url_name(url), &context)
+                        .map_err(|err| fail(&format!("URL {}", urls[url]), err))?,
+                    false => urls[url].clone(),
+                };
+                if !config.query_params.is_empty() {
+                    with_query(&target, keys(&config.query_params).zip(&query))
+                        .map_err(|err| format!("URL {}: {}", target, err))?;
+                }
+            }
+            for (payload, templated) in templates.payloads.iter().enumerate() {
+                if *templated {
+                    templates
+                        .tera
+                        .render(&payload_name(payload), &context)
+                        .map_err(|err| fail(&payload_label(payload), err))?;
+                }
+            }
+        }
+
+        // Nothing changes between requests, so the query is appended once
+        let mut urls = urls.clone();
+        if !templates.query.contains(&true) && !config.query_params.is_empty() {
+            for (url, templated) in urls.iter_mut().zip(&templates.urls) {
+                if !*templated {
+                    let query = config.query_params.iter().map(|(key, value)| (key, value));
+                    *url = with_query(url, query).expect("checked above");
+                }
+            }
+        }
+
+        Ok(RequestSource {
+            urls,
+            query: config.query_params.clone(),
+            payloads,
+            payload_strategy: config.payload_strategy,
+            payload_cursor: AtomicUsize::new(0),
+            data,
+            data_strategy: config.data_strategy,
+            data_mode: config.data_mode,
+            ids: Ids {
+                start: config.path_param_start,
+                step: config.path_param_step,
+                next: AtomicUsize::new(0),
+                planned: (config.requests_per_thread)
+                    .map(|requests_per_thread| config.num_threads * requests_per_thread),
+            },
+            templates,
+        })
+    }
+
+    pub fn data(&self) -> Option<&DataFeed> {
+        self.data.as_ref()
+    }
+
+    /// Whether anything is rendered per request.
+    pub fn is_templated(&self) -> bool {
+        self.templates.payloads.contains(&true)
+            || self.templates.urls.contains(&true)
+            || self.templates.query.contains(&true)
+    }
+
+    /// Row of the data file for the next request, `Ok(None)` without a data
+    /// file. Fails once `DataMode::Stop` used up the rows.
+    pub fn pick_row(&self, rng: &mut fastrand::Rng) -> Result<Option<&Row>, Exhausted> {
+        let Some(data) = &self.data else {
+            return Ok(None);
+        };
+        match (self.data_strategy, self.data_mode) {
+            (PickStrategy::RoundRobin, DataMode::Cycle) => Ok(Some(data.next_row())),
+            (PickStrategy::RoundRobin, DataMode::Stop) => {
+                data.take_row().map(Some).ok_or(Exhausted)
+            }
+            (PickStrategy::Random, _) => Ok(Some(&data.rows()[rng.usize(..data.rows().len())])),
+        }
+    }
+
+    /// Index of the payload for the next request, 0 with a single one.
+    pub fn pick_payload(&self, rng: &mut fastrand::Rng) -> usize {
+        match (self.payloads.len(), self.payload_strategy) {
+            (0 | 1, _) => 0,
+            (count, PickStrategy::RoundRobin) => {
+                self.payload_cursor.fetch_add(1, Ordering::Relaxed) % count
+            }
+            (count, PickStrategy::Random) => rng.usize(..count),
+        }
+    }
+
+    /// URL and body of a request to the `url`th target URL.
+    pub fn prepare(&self, url: usize, vars: &Vars) -> (String, Option<Vec<u8>>) {
+        let templates = &self.templates;
+        if !self.is_templated() {
+            return (
+                self.urls[url].clone(),
+                vars.payload
+                    .and_then(|payload| self.payloads.get(payload))
+                    .cloned(),
+            );
+        }
+        let (id, seq) = self.ids.next();
+        let context = templates.context(vars, id, seq);
+        let mut target = match templates.urls[url] {
+            true => templates.render(&url_name(url), &context),
+            false => self.urls[url].clone(),
+        };
+        if !self.query.is_empty() && (templates.urls[url] || templates.query.contains(&true)) {
+            let values: Vec<String> = (self.query.iter().enumerate())
+                .map(|(index, (_, value))| match templates.query[index] {
+                    true => templates.render(&query_name(index), &context),
+                    false => value.clone(),
+                })
+                .collect();
+            // A URL that doesn't parse fails as a request error, query or not
+            if let Ok(with_query) = with_query(&target, keys(&self.query).zip(&values)) {
+                target = with_query;
+            }
+        }
+        let body = vars
+            .payload
+            .and_then(|payload| match templates.payloads.get(payload)? {
+                true => Some(
+                    templates
+                        .render(&payload_name(payload), &context)
+                        .into_bytes(),
+                ),
+                false => Some(self.payloads[payload].clone()),
+            });
+        (target, body)
+    }
+}
+
+/// Every row of the data file was used and `DATA_MODE=stop`.
+pub struct Exhausted;
+
+/// The `{{ id }}` counter, shared by every thread so that no two requests
+/// get the same id.
+struct Ids {
+    start: usize,
+    step: usize,
+    /// Ids handed out so far.
+    next: AtomicUsize,
+    /// Requests the run is configured for, `None` in duration mode.
+    planned: Option<usize>,
+}
+
+impl Ids {
+    /// The next id, and the 1-based count of the ids handed out with it.
+    fn next(&self) -> (usize, usize) {
+        let count = self.next.fetch_add(1, Ordering::Relaxed);
+        // Only the first id past the planned ones sees this count
+        if self.planned == Some(count) {
+            esay!(
+                "Warning: more than the {} configured requests took an {{{{ id }}}} (warmup requests take one too), ids now go past {}",
+                count,
+                self.id(count - 1)
+            );
+        }
+        (self.id(count), count + 1)
+    }
+
+    fn id(&self, count: usize) -> usize {
+        self.start.wrapping_add(self.step.wrapping_mul(count))
+    }
+}
+
+struct Templates {
+    tera: Tera,
+    /// Which of the target URLs are templates, by index.
+    urls: Vec<bool>,
+    /// Which of the query parameter values are templates, by index.
+    query: Vec<bool>,
+    /// Which of the payloads are templates, by index.
+    payloads: Vec<bool>,
+}
+
+impl Templates {
+    /// Data columns first, so the built-in variables win a name clash.
+    fn context(&self, vars: &Vars, id: usize, seq: usize) -> Context {
+        let mut context = Context::new();
+        if let Some(row) = vars.row {
+            for (column, value) in row {
+                context.insert(column, value);
+            }
+            context.insert("csv", row);
+        }
+        context.insert("thread_id", &vars.thread_id);
+        context.insert("request_num", &vars.request_num);
+        context.insert("id", &id);
+        context.insert("seq", &seq);
+        context.insert("uuid", &new_uuid());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        context.insert("timestamp_ms", &(now.as_millis() as u64));
+        context
+    }
+
+    /// Every template rendered fine at startup; the remaining ways to fail,
+    /// such as a function erroring on some random value, are bugs.
+    fn render(&self, name: &str, context: &Context) -> String {
+        self.tera
+            .render(name, context)
+            .unwrap_or_else(|err| panic!("rendering {} failed: {}", name, describe(&err)))
+    }
+}
+
+fn url_name(index: usize) -> String {
+    format!("url{}", index)
+}
+
+fn query_name(index: usize) -> String {
+    format!("query{}", index)
+}
+
+fn payload_name(index: usize) -> String {
+    format!("payload{}", index)
+}
+
+fn keys(query: &[(String, String)]) -> impl Iterator<Item = &String> {
+    query.iter().map(|(key, _)| key)
+}
+
+/// `url` with `pairs` added to its query string, percent-encoded.
+fn with_query<K, V>(url: &str, pairs: impl Iterator<Item = (K, V)>) -> Result<String, String>
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut url = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+    url.query_pairs_mut().extend_pairs(pairs);
+    Ok(url.into())
+}
+
+pub fn is_template(text: &str) -> bool {
+    text.contains("{{") || text.contains("{%")
+}
+
+/// Tera's own message only names the template; the cause is in the chain.
+fn describe(err: &tera::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
+}
+
+/// `fake_name()`, `fake_email()` and friends: a new random value in `locale`
+/// on every call.
+fn register_fakers(tera: &mut Tera, locale: FakeLocale) {
+    match locale {
+        FakeLocale::EnUs => register_fakers_in(tera, locales::EN),
+        FakeLocale::FrFr => register_fakers_in(tera, locales::FR_FR),
+        FakeLocale::DeDe => register_fakers_in(tera, locales::DE_DE),
+        FakeLocale::ItIt => register_fakers_in(tera, locales::IT_IT),
+        FakeLocale::PtBr => register_fakers_in(tera, locales::PT_BR),
+        FakeLocale::PtPt => register_fakers_in(tera, locales::PT_PT),
+        FakeLocale::JaJp => register_fakers_in(tera, locales::JA_JP),
+        FakeLocale::ZhCn => register_fakers_in(tera, locales::ZH_CN),
+        FakeLocale::ZhTw => register_fakers_in(tera, locales::ZH_TW),
+        FakeLocale::ArSa => register_fakers_in(tera, locales::AR_SA),
+        FakeLocale::CyGb => register_fakers_in(tera, locales::CY_GB),
+    }
+}
+
+fn register_fakers_in<L>(tera: &mut Tera, locale: L)
+where
+    L: Data + CityNameGenFn + Copy + Send + Sync + 'static,
+{
+    use fake::faker::address::raw::{CityName, CountryName, StreetName, ZipCode};
+    use fake::faker::company::raw::CompanyName;
+    use fake::faker::internet::raw::{SafeEmail, Username};
+    use fake::faker::name::raw::{FirstName, LastName, Name};
+    use fake::faker::phone_number::raw::PhoneNumber;
+
+    let mut add = |name: &str, generate: fn(L) -> String| {
+        tera.register_function(name, move |_: &HashMap<String, Value>| {
+            Ok(Value::String(generate(locale)))
+        });
+    };
+    add("fake_name", |locale| Name(locale).fake());
+    add("fake_first_name", |locale| FirstName(locale).fake());
+    add("fake_last_name", |locale| LastName(locale).fake());
+    add("fake_email", |locale| SafeEmail(locale).fake());
+    add("fake_username", |locale| Username(locale).fake());
+    add("fake_phone", |locale| PhoneNumber(locale).fake());
+    add("fake_street", |locale| StreetName(locale).fake());
+    add("fake_city", |locale| CityName(locale).fake());
+    add("fake_zip_code", |locale| ZipCode(locale).fake());
+    add("fake_country", |locale| CountryName(locale).fake());
+    add("fake_company", |locale| CompanyName(locale).fake());
+    tera.register_function("fake_uuid", uuid);
+}
+
+/// `uuid()`: a random version 4 UUID.
+fn uuid(_: &HashMap<String, Value>) -> tera::Result<Value> {
+    Ok(Value::String(new_uuid()))
+}
+
+fn new_uuid() -> String {
+    let mut bytes: [u8; 16] = fastrand::u128(..).to_be_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// `rand_int(min=1, max=1000)`: a random integer between both, inclusive.
+fn rand_int(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let bound = |name: &str| {
+        args.get(name)
+            .and_then(Value::as_i64)
+            .ok_or_else(|| tera::Error::msg(format!("rand_int needs an integer `{}`", name)))
+    };
+    let (min, max) = (bound("min")?, bound("max")?);
+    if min > max {
+        return Err(tera::Error::msg(format!(
+            "rand_int needs min <= max, got {} and {}",
+            min, max
+        )));
+    }
+    Ok(Value::from(fastrand::i64(min..=max)))
+}
+
+/// Tera only takes named arguments, so `rand_int(1, 1000)` is rewritten to
+/// `rand_int(min=1, max=1000)` before compiling; anything else is left as is.
+fn named_args(text: &str) -> Cow<'_, str> {
+    const CALL: &str = "rand_int(";
+    if !text.contains(CALL) {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(CALL) {
+        let (before, call) = rest.split_at(start + CALL.len());
+        out.push_str(before);
+        rest = call;
+        let Some(end) = call.find(')') else { continue };
+        let positional = call[..end]
+            .split_once(',')
+            .map(|(min, max)| (min.trim(), max.trim()))
+            .filter(|(min, max)| min.parse::<i64>().is_ok() && max.parse::<i64>().is_ok());
+        if let Some((min, max)) = positional {
+            out.push_str(&format!("min={}, max={}", min, max));
+            rest = &call[end..];
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
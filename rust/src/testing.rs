@@ -0,0 +1,257 @@
+//! Helpers for the unit tests: a minimal HTTP/1.1 server on std's
+//! TcpListener, like the metrics server, optionally behind rustls with a
+//! certificate of the test CA in `testdata/`, a SOCKS5 proxy and temporary
+//! files.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The CA that issued the certificate of the https:// server, for localhost
+/// and 127.0.0.1; both are valid until 2126 and trusted nowhere else.
+pub const TEST_CA_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/ca.pem");
+const SERVER_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.pem");
+const SERVER_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.key");
+
+/// A request as the server received it.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// In the order received, names in lower case.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Every value of the header `name`, in order.
+    pub fn header_values(&self, name: &str) -> Vec<&str> {
+        (self.headers.iter())
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_values(name).first().copied()
+    }
+}
+
+/// Answers every request with the same status and body, on a port of its
+/// own, and keeps what it was sent. Runs until the test process exits.
+pub struct TestServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    pub fn start(status: u16, body: &'static str) -> TestServer {
+        TestServer::listen(None, status, body)
+    }
+
+    /// The same over https://, with a certificate issued by `TEST_CA_CERT`.
+    pub fn start_tls(status: u16, body: &'static str) -> TestServer {
+        let certs = rustls_pemfile::certs(&mut &fs::read(SERVER_CERT).unwrap()[..]).unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut &fs::read(SERVER_KEY).unwrap()[..])
+            .unwrap()
+            .remove(0);
+        let tls = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                certs.into_iter().map(rustls::Certificate).collect(),
+                rustls::PrivateKey(key),
+            )
+            .unwrap();
+        TestServer::listen(Some(Arc::new(tls)), status, body)
+    }
+
+    fn listen(
+        tls: Option<Arc<rustls::ServerConfig>>,
+        status: u16,
+        body: &'static str,
+    ) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let url = format!("{}://{}/", scheme, listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let received = Arc::clone(&received);
+                let tls = tls.clone();
+                thread::spawn(move || match tls {
+                    Some(tls) => {
+                        let connection = rustls::ServerConnection::new(tls).unwrap();
+                        let stream = rustls::StreamOwned::new(connection, stream);
+                        serve(stream, status, body, &received)
+                    }
+                    None => serve(stream, status, body, &received),
+                });
+            }
+        });
+        TestServer { url, requests }
+    }
+
+    /// The requests received so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Answers the requests of one keep-alive connection until the client
+/// closes it.
+fn serve(stream: impl Read + Write, status: u16, body: &str, received: &Mutex<Vec<Request>>) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+            _ => return,
+        };
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+        let length = (headers.iter())
+            .find(|(name, _)| name == "content-length")
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or(0);
+        let mut request_body = vec![0; length];
+        if reader.read_exact(&mut request_body).is_err() {
+            return;
+        }
+        received.lock().unwrap().push(Request {
+            method,
+            path,
+            headers,
+            body: request_body,
+        });
+        let response = format!(
+            "HTTP/1.1 {} Test\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        let stream = reader.get_mut();
+        if stream.write_all(response.as_bytes()).is_err() || stream.flush().is_err() {
+            return;
+        }
+    }
+}
+
+/// A SOCKS5 proxy (RFC 1928) relaying to any target, with or without a
+/// username and password (RFC 1929), which are taken as they come. Keeps
+/// `user:password@host:port` per connection, without `user:password@` when
+/// the client didn't authenticate. Runs until the test process exits.
+pub struct Socks5Proxy {
+    pub url: String,
+    connections: Arc<Mutex<Vec<String>>>,
+}
+
+impl Socks5Proxy {
+    pub fn start() -> Socks5Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("socks5h://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&connections);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let seen = Arc::clone(&seen);
+                thread::spawn(move || {
+                    if let Some((target, upstream)) = socks5_handshake(&stream) {
+                        seen.lock().unwrap().push(target);
+                        relay(stream, upstream);
+                    }
+                });
+            }
+        });
+        Socks5Proxy { url, connections }
+    }
+
+    /// The connections made so far.
+    pub fn connections(&self) -> Vec<String> {
+        self.connections.lock().unwrap().clone()
+    }
+}
+
+/// Negotiates the method, authenticates and connects to the requested target,
+/// returning it and the connection to it.
+fn socks5_handshake(mut stream: &TcpStream) -> Option<(String, TcpStream)> {
+    let read = |mut stream: &TcpStream, len: usize| {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).ok().map(|_| buf)
+    };
+    let greeting = read(stream, 2)?;
+    let methods = read(stream, greeting[1] as usize)?;
+    let mut target = String::new();
+    if methods.contains(&2) {
+        stream.write_all(&[5, 2]).ok()?;
+        let version_and_len = read(stream, 2)?;
+        let user = read(stream, version_and_len[1] as usize)?;
+        let password_len = read(stream, 1)?[0] as usize;
+        let password = read(stream, password_len)?;
+        target = format!(
+            "{}:{}@",
+            String::from_utf8_lossy(&user),
+            String::from_utf8_lossy(&password)
+        );
+        stream.write_all(&[1, 0]).ok()?;
+    } else {
+        stream.write_all(&[5, 0]).ok()?;
+    }
+    // Version, CONNECT, reserved, address type
+    let request = read(stream, 4)?;
+    let host = match request[3] {
+        1 => std::net::Ipv4Addr::from(<[u8; 4]>::try_from(read(stream, 4)?).ok()?).to_string(),
+        3 => {
+            let len = read(stream, 1)?[0] as usize;
+            String::from_utf8(read(stream, len)?).ok()?
+        }
+        _ => return None,
+    };
+    let port = read(stream, 2)?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let upstream = TcpStream::connect((host.as_str(), port)).ok()?;
+    stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).ok()?;
+    target.push_str(&format!("{}:{}", host, port));
+    Some((target, upstream))
+}
+
+/// Copies both ways until either side closes.
+fn relay(client: TcpStream, upstream: TcpStream) {
+    let (mut client_in, mut upstream_out) =
+        (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+    thread::spawn(move || {
+        let _ = std::io::copy(&mut client_in, &mut upstream_out);
+        let _ = upstream_out.shutdown(std::net::Shutdown::Write);
+    });
+    let (mut upstream_in, mut client_out) = (upstream, client);
+    let _ = std::io::copy(&mut upstream_in, &mut client_out);
+    let _ = client_out.shutdown(std::net::Shutdown::Write);
+}
+
+/// Writes `contents` to a file of the system's temp directory, named after
+/// `name` and this process.
+pub fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("load-tester-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path
+}
@@ -0,0 +1,121 @@
+use crate::json_path::JsonPath;
+use crate::oauth2::Token;
+use reqwest::StatusCode;
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+/// Gets a new bearer token from TOKEN_REFRESH_URL once a request is rejected
+/// with 401 Unauthorized.
+pub struct TokenRefresh {
+    pub url: String,
+    /// JSON body of the refresh request, none without one.
+    pub body: Option<String>,
+    /// Where the new token is in the response.
+    pub field: JsonPath,
+    /// Held while a refresh is under way, so that the 401s of concurrent
+    /// requests wait for it instead of sending refreshes of their own.
+    lock: Mutex<()>,
+}
+
+/// What a worker's call to refresh the token did.
+pub enum Refresh {
+    /// This call got a new token.
+    Refreshed,
+    /// Another worker replaced the rejected token meanwhile.
+    Replaced,
+    Failed(String),
+}
+
+impl TokenRefresh {
+    pub fn new(url: String, body: Option<String>, field: JsonPath) -> TokenRefresh {
+        TokenRefresh {
+            url,
+            body,
+            field,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Replaces `token` with a new one, unless it no longer is the
+    /// `rejected` one because another worker refreshed it meanwhile.
+    pub async fn refresh(
+        &self,
+        client: &reqwest::Client,
+        token: &Token,
+        rejected: Option<&str>,
+    ) -> Refresh {
+        let _refreshing = self.lock.lock().await;
+        if token.get().as_deref() != rejected {
+            return Refresh::Replaced;
+        }
+        let mut request = client.post(&self.url).header("Accept", "application/json");
+        if let Some(body) = &self.body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+        }
+        let response = async {
+            let response = request.send().await?;
+            let status = response.status();
+            Ok::<_, reqwest::Error>((status, response.bytes().await?))
+        }
+        .await;
+        self.finish(token, response)
+    }
+
+    /// `refresh` for the thread-per-worker engine.
+    #[cfg(feature = "blocking")]
+    pub fn refresh_blocking(
+        &self,
+        client: &reqwest::blocking::Client,
+        token: &Token,
+        rejected: Option<&str>,
+    ) -> Refresh {
+        let _refreshing = self.lock.blocking_lock();
+        if token.get().as_deref() != rejected {
+            return Refresh::Replaced;
+        }
+        let mut request = client.post(&self.url).header("Accept", "application/json");
+        if let Some(body) = &self.body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+        }
+        let response = request.send().and_then(|response| {
+            let status = response.status();
+            Ok((status, response.bytes()?))
+        });
+        self.finish(token, response)
+    }
+
+    /// Stores the token of a refresh response.
+    fn finish(
+        &self,
+        token: &Token,
+        response: Result<(StatusCode, bytes::Bytes), reqwest::Error>,
+    ) -> Refresh {
+        let (status, body) = match response {
+            Ok(response) => response,
+            Err(err) => return Refresh::Failed(err.to_string()),
+        };
+        if !status.is_success() {
+            return Refresh::Failed(format!("status {}", status));
+        }
+        let root: Value = match serde_json::from_slice(&body) {
+            Ok(root) => root,
+            Err(err) => {
+                return Refresh::Failed(format!("status {}, body is not JSON: {}", status, err))
+            }
+        };
+        match self.field.select(&root) {
+            Some(Value::String(new)) if !new.is_empty() => {
+                token.set(new.clone());
+                Refresh::Refreshed
+            }
+            _ => Refresh::Failed(format!(
+                "status {}, no token at {} in the response",
+                status, self.field
+            )),
+        }
+    }
+}
@@ -0,0 +1,531 @@
+use crate::console::Captured;
+use crate::engine::Stop;
+use crate::progress::Goal;
+use crate::stats::{Activity, Counters, LATENCY_BUCKETS, LATENCY_BUCKET_US};
+use ::console::{measure_text_width, truncate_str, Style, Term};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const TICK: Duration = Duration::from_millis(100);
+const TICKS_PER_RATE: u32 = 10;
+/// RPS samples kept for the sparkline, more than any terminal is wide.
+const HISTORY: usize = 512;
+const SPARKLINE_ROWS: usize = 3;
+const MAX_THREAD_ROWS: usize = 3;
+/// Error log lines a PgUp or PgDn scrolls by.
+const PAGE: usize = 10;
+
+/// Whether the dashboard has the terminal, see `restore`.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Full-screen view of the run, redrawn 10 times a second: RPS over time,
+/// the latency distribution, what every worker is doing and the failed
+/// requests. Output of `say!` and `esay!` is held back while it's shown.
+pub struct Dashboard {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+    screen: Screen,
+}
+
+/// Gives the terminal back when dropped, also while unwinding from a panic.
+struct Screen;
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+impl Dashboard {
+    pub fn start(counters: Arc<Counters>, stop_run: Stop, goal: Goal) -> Dashboard {
+        let keys = input::enter();
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        let _ = io::stdout().flush();
+        ACTIVE.store(true, Ordering::Relaxed);
+        let screen = Screen;
+        let captured = Arc::new(Captured::default());
+        crate::console::capture(Arc::clone(&captured));
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut view = View::new(counters, stop_run, captured, goal);
+            let mut last_draw = Instant::now() - TICK;
+            let mut ticks = 0;
+            // Woken up early (or orphaned) means the run is over
+            while let Err(TryRecvError::Empty) = stopped.try_recv() {
+                let pressed = if keys {
+                    input::read_key()
+                } else {
+                    thread::sleep(TICK);
+                    None
+                };
+                if let Some(key) = pressed {
+                    view.press(key);
+                }
+                if last_draw.elapsed() >= TICK {
+                    last_draw = Instant::now();
+                    ticks += 1;
+                    if ticks % TICKS_PER_RATE == 0 {
+                        view.sample_rate();
+                    }
+                } else if pressed.is_none() {
+                    continue;
+                }
+                view.draw();
+            }
+        });
+        Dashboard {
+            stop,
+            handle,
+            screen,
+        }
+    }
+
+    /// Stops redrawing and gives the terminal back as it was. What the error
+    /// log showed isn't printed again, the summary counts the errors.
+    pub fn finish(self) {
+        let _ = self.stop.send(());
+        self.handle.join().expect("dashboard thread panicked");
+        drop(self.screen);
+        crate::console::release();
+    }
+}
+
+/// Leaves the dashboard's screen and restores the terminal settings, for
+/// exits in the middle of a run. Does nothing without a dashboard.
+pub fn restore() {
+    if !ACTIVE.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    print!("\x1b[?25h\x1b[?1049l");
+    let _ = io::stdout().flush();
+    input::leave();
+}
+
+#[derive(Clone, Copy)]
+enum Key {
+    Quit,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    /// Back to following the newest errors.
+    End,
+}
+
+/// What the dashboard shows, and how far the error log is scrolled back.
+struct View {
+    counters: Arc<Counters>,
+    /// Shows when the run is stopping.
+    stop: Stop,
+    captured: Arc<Captured>,
+    goal: Goal,
+    started: Instant,
+    /// Requests per second, one sample a second, the newest last.
+    rates: VecDeque<f64>,
+    /// When the last rate was sampled, and how many requests had completed
+    /// by then.
+    last_rate: (Instant, u64),
+    /// Error log lines hidden below the panel, 0 to follow the newest.
+    scroll: usize,
+}
+
+impl View {
+    fn new(counters: Arc<Counters>, stop: Stop, captured: Arc<Captured>, goal: Goal) -> View {
+        let now = Instant::now();
+        View {
+            counters,
+            stop,
+            captured,
+            goal,
+            started: now,
+            rates: VecDeque::with_capacity(HISTORY),
+            last_rate: (now, 0),
+            scroll: 0,
+        }
+    }
+
+    fn sample_rate(&mut self) {
+        let now = Instant::now();
+        let done = self.counters.snapshot().completed();
+        let seconds = (now - self.last_rate.0).as_secs_f64();
+        if self.rates.len() == HISTORY {
+            self.rates.pop_front();
+        }
+        self.rates
+            .push_back((done - self.last_rate.1) as f64 / seconds);
+        self.last_rate = (now, done);
+    }
+
+    fn press(&mut self, key: Key) {
+        match key {
+            // The graceful stop of the first Ctrl+C, for this run only
+            Key::Quit => {
+                self.stop.request();
+            }
+            Key::Up => self.scroll += 1,
+            Key::Down => self.scroll = self.scroll.saturating_sub(1),
+            Key::PageUp => self.scroll += PAGE,
+            Key::PageDown => self.scroll = self.scroll.saturating_sub(PAGE),
+            Key::End => self.scroll = 0,
+        }
+    }
+
+    fn draw(&mut self) {
+        let (height, width) = Term::stdout().size();
+        let lines = self.lines(width as usize, height as usize);
+        let mut frame = String::from("\x1b[H");
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                frame.push('\n');
+            }
+            frame.push_str(&truncate_str(line, width as usize, ""));
+            frame.push_str("\x1b[K");
+        }
+        frame.push_str("\x1b[J");
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(frame.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    /// The screen, line by line, before truncation to `width`.
+    fn lines(&mut self, width: usize, height: usize) -> Vec<String> {
+        let bold = Style::new().bold();
+        let dim = Style::new().dim();
+        let counted = self.counters.snapshot();
+        let (success, failure) = (counted.success, counted.failure);
+        let done = counted.completed();
+        let elapsed = self.started.elapsed();
+        let mut lines = Vec::with_capacity(height);
+
+        let progress = match self.goal {
+            Goal::Requests(total) => format!(
+                "{}/{} requests ({:.0}%)",
+                done,
+                total,
+                done as f64 * 100.0 / total.max(1) as f64
+            ),
+            Goal::Duration(duration) => format!(
+                "{:.0}s / {}s ({:.0}%)",
+                elapsed.as_secs_f64(),
+                duration.as_secs(),
+                (elapsed.as_secs_f64() * 100.0 / duration.as_secs_f64()).min(100.0)
+            ),
+        };
+        let hint = if self.stop.requested() {
+            Style::new()
+                .yellow()
+                .apply_to("Stopping, waiting for in-flight requests")
+        } else {
+            dim.apply_to("q or Ctrl+C: stop | ↑ ↓ PgUp PgDn End: scroll errors")
+        };
+        lines.push(format!(
+            "{} | {} | {}",
+            bold.apply_to("Load test"),
+            progress,
+            hint
+        ));
+        let avg_ms = match done {
+            0 => 0.0,
+            _ => counted.latency_us as f64 / done as f64 / 1000.0,
+        };
+        lines.push(format!(
+            "Requests: {} | {} | {} | {:.2} RPS overall | avg {:.2} ms",
+            done,
+            Style::new()
+                .green()
+                .apply_to(format!("Success: {}", success)),
+            Style::new().red().apply_to(format!("Failure: {}", failure)),
+            done as f64 / elapsed.as_secs_f64().max(0.001),
+            avg_ms
+        ));
+
+        let peak = self.rates.iter().copied().fold(0.0, f64::max);
+        lines.push(section(
+            &format!(
+                "RPS, last {:.2} | peak {:.2}",
+                self.rates.back().copied().unwrap_or(0.0),
+                peak
+            ),
+            width,
+        ));
+        lines.extend(sparkline(&self.rates, width, peak));
+
+        let activity = self.counters.activity();
+        let count = |state| activity.iter().filter(|&&a| a == state).count();
+        let thread_title = format!(
+            "Threads, {} sending | {} waiting | {} done",
+            count(Activity::Sending),
+            count(Activity::Waiting),
+            count(Activity::Done)
+        );
+        let threads = thread_grid(&activity, width);
+
+        // Whatever the fixed panels leave goes to the latency histogram and
+        // the error log, which gets at least 3 lines
+        let fixed = lines.len() + 1 + 1 + threads.len() + 1;
+        let histogram_rows = height.saturating_sub(fixed + 3).max(1);
+        lines.push(section("Latency, 10 ms buckets", width));
+        lines.extend(histogram(&counted.latency_buckets, width, histogram_rows));
+
+        lines.push(section(&thread_title, width));
+        lines.extend(threads);
+
+        let errors = self
+            .captured
+            .lines
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let rows = height.saturating_sub(lines.len() + 1);
+        self.scroll = self.scroll.min(errors.len().saturating_sub(rows));
+        let end = errors.len() - self.scroll;
+        let title = match self.scroll {
+            0 => format!("Errors, {} lines", errors.len()),
+            scroll => format!("Errors, {} lines | {} newer below", errors.len(), scroll),
+        };
+        lines.push(section(&title, width));
+        let red = Style::new().red();
+        lines.extend(
+            (errors.range(end.saturating_sub(rows)..end))
+                .map(|line| red.apply_to(line).to_string()),
+        );
+        lines.truncate(height);
+        lines
+    }
+}
+
+/// Panel title, padded with a rule to `width`.
+fn section(title: &str, width: usize) -> String {
+    let title = format!("── {} ", title);
+    let rule = "─".repeat(width.saturating_sub(measure_text_width(&title)));
+    Style::new()
+        .cyan()
+        .apply_to(format!("{}{}", title, rule))
+        .to_string()
+}
+
+/// `SPARKLINE_ROWS` lines charting the newest `rates` that fit in `width`,
+/// scaled to `peak`.
+fn sparkline(rates: &VecDeque<f64>, width: usize, peak: f64) -> Vec<String> {
+    const BLOCKS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let shown: Vec<usize> = (rates.iter().skip(rates.len().saturating_sub(width)))
+        .map(|rate| match peak {
+            0.0 => 0,
+            _ => (rate / peak * (SPARKLINE_ROWS * 8) as f64).round() as usize,
+        })
+        .collect();
+    (0..SPARKLINE_ROWS)
+        .rev()
+        .map(|row| {
+            (shown.iter())
+                .map(|eighths| BLOCKS[eighths.saturating_sub(row * 8).min(8)])
+                .collect()
+        })
+        .collect()
+}
+
+/// One bar per latency bucket, up to the slowest one with any requests,
+/// folding whatever doesn't fit in `rows` into the last bar.
+fn histogram(buckets: &[u64], width: usize, rows: usize) -> Vec<String> {
+    let used = buckets
+        .iter()
+        .rposition(|&n| n > 0)
+        .map_or(1, |last| last + 1);
+    let shown = used.min(rows);
+    let mut bars: Vec<(String, u64)> = (0..shown)
+        .map(|bucket| {
+            let from = bucket as u64 * LATENCY_BUCKET_US / 1000;
+            let label = if bucket == LATENCY_BUCKETS - 1 {
+                format!("{}+ ms", from)
+            } else {
+                format!("{}-{} ms", from, from + LATENCY_BUCKET_US / 1000)
+            };
+            (label, buckets[bucket])
+        })
+        .collect();
+    if shown < used {
+        let last = shown - 1;
+        bars[last] = (
+            format!("{}+ ms", last as u64 * LATENCY_BUCKET_US / 1000),
+            buckets[last..].iter().sum(),
+        );
+    }
+    let most = bars.iter().map(|(_, n)| *n).max().unwrap_or(0).max(1);
+    let room = width.saturating_sub(10 + 1 + 10);
+    bars.into_iter()
+        .map(|(label, n)| {
+            let length = (n as f64 / most as f64 * room as f64).round() as usize;
+            format!("{:>10} {} {}", label, "█".repeat(length), n)
+        })
+        .collect()
+}
+
+/// A dot per worker, colored by what it's doing, over up to
+/// `MAX_THREAD_ROWS` lines.
+fn thread_grid(activity: &[Activity], width: usize) -> Vec<String> {
+    let per_row = (width / 2).max(1);
+    let fits = per_row * MAX_THREAD_ROWS;
+    let mut cells: Vec<String> = (activity.iter().take(fits))
+        .map(|state| match state {
+            Activity::Sending => Style::new().green().apply_to("●").to_string(),
+            Activity::Waiting => Style::new().yellow().apply_to("●").to_string(),
+            Activity::NotStarted => Style::new().dim().apply_to("·").to_string(),
+            Activity::Done => Style::new().dim().apply_to("✓").to_string(),
+        })
+        .collect();
+    if activity.len() > fits {
+        cells.truncate(fits - 1);
+        cells.push(format!("+{}", activity.len() - fits + 1));
+    }
+    cells.chunks(per_row).map(|row| row.join(" ")).collect()
+}
+
+/// Raw-ish terminal input for the keys: no line buffering nor echo, but
+/// Ctrl+C still raises SIGINT for the usual handler.
+#[cfg(unix)]
+mod input {
+    use super::Key;
+    use std::io::{self, IsTerminal};
+    use std::sync::Mutex;
+
+    /// Settings from before `enter`, put back by `leave`.
+    static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+    /// Whether keys can be read, i.e. stdin is a terminal that took the new
+    /// settings.
+    pub fn enter() -> bool {
+        if !io::stdin().is_terminal() {
+            return false;
+        }
+        let mut termios = std::mem::MaybeUninit::uninit();
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } != 0 {
+            return false;
+        }
+        let saved = unsafe { termios.assume_init() };
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        // Reads give up after a tick without input
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = (super::TICK.as_millis() / 100) as libc::cc_t;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return false;
+        }
+        *SAVED.lock().unwrap_or_else(|err| err.into_inner()) = Some(saved);
+        true
+    }
+
+    pub fn leave() {
+        if let Some(saved) = SAVED.lock().unwrap_or_else(|err| err.into_inner()).take() {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+        }
+    }
+
+    /// The next key, waiting for at most a tick.
+    pub fn read_key() -> Option<Key> {
+        let mut buf = [0u8; 8];
+        let read = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+        match &buf[..read.max(0) as usize] {
+            [b'q' | b'Q', ..] => Some(Key::Quit),
+            [0x1b, b'[', b'A', ..] | [b'k', ..] => Some(Key::Up),
+            [0x1b, b'[', b'B', ..] | [b'j', ..] => Some(Key::Down),
+            [0x1b, b'[', b'5', b'~', ..] => Some(Key::PageUp),
+            [0x1b, b'[', b'6', b'~', ..] | [b' ', ..] => Some(Key::PageDown),
+            [0x1b, b'[', b'F' | b'4', ..] | [b'G', ..] => Some(Key::End),
+            _ => None,
+        }
+    }
+}
+
+/// Without termios only Ctrl+C stops the run and the error log can't be
+/// scrolled.
+#[cfg(not(unix))]
+mod input {
+    use super::Key;
+
+    pub fn enter() -> bool {
+        false
+    }
+
+    pub fn leave() {}
+
+    pub fn read_key() -> Option<Key> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(lines: Vec<String>) -> Vec<String> {
+        (lines.iter())
+            .map(|line| ::console::strip_ansi_codes(line).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn sparkline_scales_the_newest_rates_to_the_peak() {
+        let rates = VecDeque::from([9.0, 4.0, 2.0, 0.5]);
+        assert_eq!(sparkline(&rates, 3, 4.0), ["█  ", "█▄ ", "██▃"]);
+    }
+
+    #[test]
+    fn sparkline_is_blank_without_a_peak() {
+        let rates = VecDeque::from([0.0, 0.0]);
+        assert_eq!(sparkline(&rates, 10, 0.0), ["  ", "  ", "  "]);
+        assert_eq!(sparkline(&VecDeque::new(), 10, 0.0), ["", "", ""]);
+    }
+
+    #[test]
+    fn histogram_ends_at_the_slowest_bucket_with_requests() {
+        let mut buckets = [0; LATENCY_BUCKETS];
+        buckets[1] = 3;
+        buckets[3] = 1;
+        assert_eq!(
+            histogram(&buckets, 31, 10),
+            [
+                "   0-10 ms  0",
+                "  10-20 ms ██████████ 3",
+                "  20-30 ms  0",
+                "  30-40 ms ███ 1",
+            ]
+        );
+        // Nothing counted yet, still one bar
+        assert_eq!(histogram(&[0; LATENCY_BUCKETS], 31, 10), ["   0-10 ms  0"]);
+    }
+
+    #[test]
+    fn histogram_folds_what_doesnt_fit_into_the_last_bar() {
+        let mut buckets = [0; LATENCY_BUCKETS];
+        buckets[..6].copy_from_slice(&[1, 1, 1, 1, 1, 2]);
+        assert_eq!(
+            histogram(&buckets, 26, 3),
+            ["   0-10 ms █ 1", "  10-20 ms █ 1", "    20+ ms █████ 5"]
+        );
+        let mut slowest = [0; LATENCY_BUCKETS];
+        slowest[LATENCY_BUCKETS - 1] = 1;
+        let bars = histogram(&slowest, 31, LATENCY_BUCKETS);
+        assert_eq!(bars.len(), LATENCY_BUCKETS);
+        assert_eq!(bars[LATENCY_BUCKETS - 1], "   190+ ms ██████████ 1");
+    }
+
+    #[test]
+    fn thread_grid_counts_the_workers_that_dont_fit() {
+        let sending = |n| vec![Activity::Sending; n];
+        assert_eq!(
+            plain(thread_grid(&sending(9), 6)),
+            ["● ● ●", "● ● ●", "● ● ●"]
+        );
+        assert_eq!(
+            plain(thread_grid(&sending(12), 6)),
+            ["● ● ●", "● ● ●", "● ● +4"]
+        );
+        let states = [Activity::NotStarted, Activity::Waiting, Activity::Done];
+        assert_eq!(plain(thread_grid(&states, 0)), ["·", "●", "✓"]);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::config::{AuthConfig, Config, WsMessageType};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub type Connection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Why a WebSocket request failed. `kind` is a short, stable name like the
+/// ones of HTTP transport errors.
+pub struct Error {
+    pub kind: &'static str,
+    message: String,
+}
+
+impl Error {
+    fn new(kind: &'static str, message: impl fmt::Display) -> Error {
+        Error {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Opens a connection to `url`, sending the credentials of the `thread_id`th
+/// thread and the extra headers of `config` with the handshake.
+/// `connect_timeout` covers the whole handshake, TLS included.
+pub async fn connect(config: &Config, url: &str, thread_id: usize) -> Result<Connection, Error> {
+    let mut request = url
+        .into_client_request()
+        .map_err(|err| Error::new("request", err))?;
+    let headers = request.headers_mut();
+    let authorization = match &config.auth {
+        AuthConfig::Basic { username, password } => {
+            let credentials = format!("{}:{}", username, password.as_deref().unwrap_or(""));
+            Some((
+                AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode(credentials)),
+            ))
+        }
+        auth => (auth.bearer_token(thread_id)).map(|token| {
+            let header = &config.token_header;
+            (header.name.clone(), header.value(&token))
+        }),
+    };
+    if let Some((name, authorization)) = authorization {
+        let value =
+            HeaderValue::from_str(&authorization).map_err(|err| Error::new("request", err))?;
+        headers.insert(name, value);
+    }
+    // Replaces the default above when set, like for HTTP requests
+    for name in config.headers.keys() {
+        headers.remove(name);
+    }
+    for (name, value) in &config.headers {
+        headers.append(name, value.clone());
+    }
+
+    match tokio::time::timeout(
+        config.connect_timeout,
+        tokio_tungstenite::connect_async(request),
+    )
+    .await
+    {
+        Ok(Ok((connection, _))) => Ok(connection),
+        Ok(Err(err)) => Err(Error::new(connect_error_kind(&err), err)),
+        Err(_) => Err(Error::new(
+            "connect_timeout",
+            format!(
+                "no connection within {} ms",
+                config.connect_timeout.as_millis()
+            ),
+        )),
+    }
+}
+
+/// The message of one request, from the (rendered) message file, which is
+/// known to be UTF-8 for text frames.
+pub fn message(config: &Config, body: Vec<u8>) -> Message {
+    match config.ws_message_type {
+        WsMessageType::Text => Message::Text(String::from_utf8_lossy(&body).into_owned()),
+        WsMessageType::Binary => Message::Binary(body),
+    }
+}
+
+/// Sends `message` and waits up to `timeout` for the first text or binary
+/// frame in reply. Control frames in between are skipped, pings are answered
+/// by tungstenite.
+pub async fn round_trip(
+    connection: &mut Connection,
+    message: Message,
+    timeout: Duration,
+) -> Result<Message, Error> {
+    let exchange = async {
+        connection.send(message).await.map_err(transfer_error)?;
+        loop {
+            match connection.next().await {
+                Some(Ok(reply @ (Message::Text(_) | Message::Binary(_)))) => return Ok(reply),
+                Some(Ok(Message::Close(frame))) => {
+                    return Err(Error::new(
+                        "closed",
+                        match frame {
+                            Some(frame) => format!(
+                                "connection closed by the server ({} {})",
+                                frame.code, frame.reason
+                            ),
+                            None => "connection closed by the server".to_string(),
+                        },
+                    ))
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(transfer_error(err)),
+                None => return Err(Error::new("closed", "connection closed by the server")),
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(res) => res,
+        Err(_) => Err(Error::new(
+            "timeout",
+            format!("no reply within {} ms", timeout.as_millis()),
+        )),
+    }
+}
+
+/// Text a reply is checked against: as is for text frames, decoded lossily
+/// for binary ones.
+pub fn reply_text(reply: &Message) -> String {
+    match reply {
+        Message::Binary(data) => String::from_utf8_lossy(data).into_owned(),
+        reply => reply.to_text().unwrap_or_default().to_string(),
+    }
+}
+
+/// Same kinds as for HTTP where they apply; a server that answers the
+/// upgrade request with anything but 101 fails the "handshake".
+fn connect_error_kind(err: &tungstenite::Error) -> &'static str {
+    match err {
+        tungstenite::Error::Io(io) if io.kind() == io::ErrorKind::ConnectionRefused => {
+            "connection_refused"
+        }
+        // The resolver's errors don't have a kind of their own
+        tungstenite::Error::Io(io) if io.to_string().contains("lookup address") => "dns",
+        // tokio-rustls reports handshake failures as invalid data
+        tungstenite::Error::Io(io) if io.kind() == io::ErrorKind::InvalidData => "tls",
+        tungstenite::Error::Tls(_) => "tls",
+        tungstenite::Error::Http(_) | tungstenite::Error::Protocol(_) => "handshake",
+        tungstenite::Error::Url(_) | tungstenite::Error::HttpFormat(_) => "request",
+        _ => "connect",
+    }
+}
+
+/// Errors on an established connection.
+fn transfer_error(err: tungstenite::Error) -> Error {
+    let kind = match err {
+        tungstenite::Error::ConnectionClosed
+        | tungstenite::Error::AlreadyClosed
+        | tungstenite::Error::Io(_) => "closed",
+        tungstenite::Error::Protocol(_) | tungstenite::Error::Utf8 => "protocol",
+        _ => "other",
+    };
+    Error::new(kind, err)
+}